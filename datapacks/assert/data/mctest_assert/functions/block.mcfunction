@@ -0,0 +1,7 @@
+# Asserts that the block at a position matches a block predicate, eg.
+# function mctest_assert:block {pos: "0 -60 0", block: "minecraft:stone"}
+# Arguments are quoted with ' in the messages, so they can't contain ' or \
+scoreboard objectives add mctest.assert dummy
+$execute store success score #passed mctest.assert if block $(pos) $(block)
+$execute if score #passed mctest.assert matches 1 run tellraw @a {"text":"pass","insertion":"mctest:assert","extra":[' block at $(pos) is $(block)']}
+$execute if score #passed mctest.assert matches 0 run tellraw @a {"text":"fail","insertion":"mctest:assert","extra":[' expected block at $(pos) to be $(block)']}
@@ -0,0 +1,8 @@
+# Asserts that the number of entities matching a selector matches a value or range, eg.
+# function mctest_assert:entity_count {selector: "@e[type=minecraft:zombie]", count: "1.."}
+# Arguments are quoted with ' in the messages, so they can't contain ' or \
+scoreboard objectives add mctest.assert dummy
+$execute store result score #count mctest.assert if entity $(selector)
+$execute store success score #passed mctest.assert if score #count mctest.assert matches $(count)
+$execute if score #passed mctest.assert matches 1 run tellraw @a {"text":"pass","insertion":"mctest:assert","extra":[' entities matching $(selector) number $(count)']}
+$execute if score #passed mctest.assert matches 0 run tellraw @a {"text":"fail","insertion":"mctest:assert","extra":[' expected $(count) entities matching $(selector) but found ',{"score":{"name":"#count","objective":"mctest.assert"}}]}
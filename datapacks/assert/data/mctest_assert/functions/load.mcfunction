@@ -0,0 +1,2 @@
+# Marks datapacks as (re)loaded for the runner waiting on `/reload`
+data modify storage mctest:runner reloaded set value 1b
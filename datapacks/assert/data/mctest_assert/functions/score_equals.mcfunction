@@ -0,0 +1,7 @@
+# Asserts that a score matches a value or range, eg.
+# function mctest_assert:score_equals {target: "#counter", objective: "vars", value: "3"}
+# Arguments are quoted with ' in the messages, so they can't contain ' or \
+scoreboard objectives add mctest.assert dummy
+$execute store success score #passed mctest.assert if score $(target) $(objective) matches $(value)
+$execute if score #passed mctest.assert matches 1 run tellraw @a {"text":"pass","insertion":"mctest:assert","extra":[' score $(target) $(objective) matches $(value)']}
+$execute if score #passed mctest.assert matches 0 run tellraw @a {"text":"fail","insertion":"mctest:assert","extra":[' expected score $(target) $(objective) to match $(value) but found ',{"score":{"name":'$(target)',"objective":'$(objective)'}}]}
@@ -0,0 +1,12 @@
+# Asserts that the NBT at a path of a storage equals a value, eg.
+# function mctest_assert:storage {storage: "example:data", path: "players[0].name", value: '"Steve"'}
+# The storage and path are quoted with ' in the messages, so they can't contain ' or \
+scoreboard objectives add mctest.assert dummy
+$data modify storage mctest_assert:values expected set value $(value)
+data modify storage mctest_assert:values compared set from storage mctest_assert:values expected
+# Setting a value fails when it's already equal
+$execute store success score #passed mctest.assert run data modify storage mctest_assert:values compared set from storage $(storage) $(path)
+execute store success score #passed mctest.assert if score #passed mctest.assert matches 0
+$execute unless data storage $(storage) $(path) run scoreboard players set #passed mctest.assert 0
+$execute if score #passed mctest.assert matches 1 run tellraw @a {"text":"pass","insertion":"mctest:assert","extra":[' storage $(storage) $(path) equals expected value']}
+$execute if score #passed mctest.assert matches 0 run tellraw @a {"text":"fail","insertion":"mctest:assert","extra":[' expected storage $(storage) $(path) to be ',{"storage":"mctest_assert:values","nbt":"expected"},' but found ',{"storage":'$(storage)',"nbt":'$(path)'}]}
@@ -0,0 +1 @@
+tellraw @s "ok - jump"
\ No newline at end of file
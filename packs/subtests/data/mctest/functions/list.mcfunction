@@ -0,0 +1,2 @@
+tellraw @s "/function mctest:standalone"
+tellraw @s "/function mctest:movement"
\ No newline at end of file
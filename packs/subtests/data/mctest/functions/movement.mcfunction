@@ -0,0 +1,4 @@
+tellraw @s "# Subtest: movement"
+tellraw @s "1..2"
+tellraw @s "/function mctest:movement/walk"
+tellraw @s "/function mctest:movement/jump"
\ No newline at end of file
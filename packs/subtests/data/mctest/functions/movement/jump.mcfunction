@@ -0,0 +1 @@
+tellraw @s "not ok - jump # TODO"
\ No newline at end of file
@@ -0,0 +1 @@
+tellraw @s "ok - walk"
\ No newline at end of file
@@ -0,0 +1 @@
+tellraw @s "1..2"
\ No newline at end of file
@@ -0,0 +1 @@
+tellraw @s "ok - standalone"
\ No newline at end of file
@@ -0,0 +1,338 @@
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::Read;
+use std::ops::RangeInclusive;
+use std::path::Path;
+use zip::ZipArchive;
+
+mod coverage;
+
+pub use coverage::{HITS as COVERAGE_HITS, HITS_STORAGE as COVERAGE_STORAGE};
+
+/// The tag listing the tests of a pack, eg. `data/mctest/tags/functions/tests.json`.
+const TESTS_TAG: &str = "mctest:tests";
+/// Directory under which functions are tests by convention, eg. `data/example/functions/test/jump.mcfunction`.
+const TEST_DIRECTORY: &str = "test/";
+/// Directory under which functions are benchmarks, eg. `data/example/functions/bench/sort.mcfunction`.
+const BENCH_DIRECTORY: &str = "bench/";
+
+/// The files of a datapack, read from a directory or zip archive.
+pub struct Datapack {
+    /// File contents by path relative to the root of the pack, separated by `/`.
+    files: BTreeMap<String, Vec<u8>>,
+}
+
+impl Datapack {
+    pub fn read(path: &Path) -> Result<Self> {
+        let mut files = BTreeMap::new();
+        if path.is_dir() {
+            read_dir(path, "", &mut files)?;
+        } else {
+            let mut archive = ZipArchive::new(File::open(path)?)?;
+            for i in 0..archive.len() {
+                let mut file = archive.by_index(i)?;
+                if file.is_dir() {
+                    continue;
+                }
+                let name = file.name().to_owned();
+                let mut content = Vec::new();
+                file.read_to_end(&mut content)?;
+                files.insert(name, content);
+            }
+        }
+
+        Ok(Datapack { files })
+    }
+
+    /// Writes the files of the pack into the directory `dir`.
+    pub fn write(&self, dir: &Path) -> Result<()> {
+        for (path, content) in &self.files {
+            let path = dir.join(path);
+            fs::create_dir_all(path.parent().unwrap_or(dir))?;
+            fs::write(path, content)?;
+        }
+        Ok(())
+    }
+
+    /// The data pack formats the pack supports according to its `pack.mcmeta`.
+    pub fn pack_formats(&self) -> Result<RangeInclusive<u32>> {
+        let content = self.files.get("pack.mcmeta").ok_or(anyhow!("Could not find pack.mcmeta"))?;
+        let PackMeta { pack } = serde_json::from_slice(content)?;
+        Ok(match pack.supported_formats {
+            None => pack.pack_format..=pack.pack_format,
+            Some(SupportedFormats::Single(format)) => format..=format,
+            Some(SupportedFormats::Range([min, max]) | SupportedFormats::Bounds { min_inclusive: min, max_inclusive: max }) => min..=max,
+        })
+    }
+
+    /// Ids of every function in the pack, eg. `mctest:test/jump`.
+    pub fn functions(&self) -> impl Iterator<Item = String> + '_ {
+        self.files.keys().filter_map(|path| resource_id(path, "functions", ".mcfunction"))
+    }
+
+    pub fn has_function(&self, id: &str) -> bool {
+        self.functions().any(|function| function == id)
+    }
+
+    /// Ids of the tests in the pack, found by the first of these conventions that applies:
+    /// 1. The functions in the `#mctest:tests` function tag
+    /// 2. None if the pack lists its tests itself, with `mctest:plan` and `mctest:list`
+    /// 3. Functions in a `test` directory of any namespace
+    pub fn discover_tests(&self) -> Result<Option<Vec<String>>> {
+        if let Some(tests) = self.function_tag(TESTS_TAG)? {
+            return Ok(Some(tests));
+        }
+
+        if self.has_function("mctest:plan") {
+            return Ok(None);
+        }
+
+        let tests = self.functions_in(TEST_DIRECTORY);
+        Ok(Some(tests).filter(|tests| !tests.is_empty()))
+    }
+
+    /// Ids of the benchmarks in the pack, the functions in a `bench` directory of any namespace.
+    pub fn discover_benchmarks(&self) -> Vec<String> {
+        self.functions_in(BENCH_DIRECTORY)
+    }
+
+    /// Ids of the functions under `directory` in any namespace.
+    fn functions_in(&self, directory: &str) -> Vec<String> {
+        self.functions()
+            .filter(|id| id.split_once(':').is_some_and(|(_, path)| path.starts_with(directory)))
+            .collect()
+    }
+
+    /// Resolves the function ids of a function tag defined by this pack, including those of nested tags.
+    fn function_tag(&self, id: &str) -> Result<Option<Vec<String>>> {
+        let Some(tag) = self.read_function_tag(id)? else {
+            return Ok(None);
+        };
+
+        let mut functions = Vec::new();
+        for entry in tag.values {
+            let (entry_id, required) = match entry {
+                TagEntry::Id(id) => (id, true),
+                TagEntry::Entry { id, required } => (id, required),
+            };
+            match entry_id.strip_prefix('#') {
+                Some(nested) => match self.function_tag(nested)? {
+                    Some(nested_functions) => functions.extend(nested_functions),
+                    None if required => return Err(anyhow!("Could not find function tag #{nested} referenced by #{id}")),
+                    None => {}
+                },
+                None => {
+                    if required || self.has_function(&entry_id) {
+                        functions.push(entry_id);
+                    }
+                }
+            }
+        }
+
+        Ok(Some(functions))
+    }
+
+    fn read_function_tag(&self, id: &str) -> Result<Option<Tag>> {
+        let (namespace, path) = id.split_once(':').unwrap_or(("minecraft", id));
+        let content = function_paths(&format!("data/{namespace}/tags/functions/{path}.json"))
+            .iter()
+            .find_map(|path| self.files.get(path));
+
+        match content {
+            Some(content) => Ok(Some(serde_json::from_slice(content)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Tag {
+    #[serde(default = "Vec::new")]
+    values: Vec<TagEntry>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum TagEntry {
+    Id(String),
+    Entry {
+        id: String,
+        #[serde(default = "default_required")]
+        required: bool,
+    },
+}
+
+#[derive(Deserialize)]
+struct PackMeta {
+    pack: Pack,
+}
+
+#[derive(Deserialize)]
+struct Pack {
+    pack_format: u32,
+    supported_formats: Option<SupportedFormats>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SupportedFormats {
+    Single(u32),
+    Range([u32; 2]),
+    Bounds { min_inclusive: u32, max_inclusive: u32 },
+}
+
+fn default_required() -> bool {
+    true
+}
+
+fn read_dir(dir: &Path, prefix: &str, files: &mut BTreeMap<String, Vec<u8>>) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = format!("{prefix}{}", entry.file_name().to_string_lossy());
+        if entry.file_type()?.is_dir() {
+            read_dir(&entry.path(), &format!("{name}/"), files)?;
+        } else {
+            files.insert(name, fs::read(entry.path())?);
+        }
+    }
+    Ok(())
+}
+
+/// The paths of a file at `path` in the layouts of every version, whose `functions` directories
+/// are named `function` in newer versions. `path` is given in the older layout.
+pub fn function_paths(path: &str) -> Vec<String> {
+    if path.contains("/functions/") {
+        vec![path.to_owned(), path.replace("/functions/", "/function/")]
+    } else {
+        vec![path.to_owned()]
+    }
+}
+
+/// Turns a path like `data/<namespace>/<kind>/<path><extension>` into the id `<namespace>:<path>`.
+/// Accepts both the plural directory names of older versions and the singular ones of newer.
+fn resource_id(path: &str, kind: &str, extension: &str) -> Option<String> {
+    let rest = path.strip_prefix("data/")?;
+    let (namespace, rest) = rest.split_once('/')?;
+    let (directory, rest) = rest.split_once('/')?;
+    if directory != kind && Some(directory) != kind.strip_suffix('s') {
+        return None;
+    }
+    let path = rest.strip_suffix(extension)?;
+    Some(format!("{namespace}:{path}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tempdir::TempDir;
+    use zip::write::{FileOptions, ZipWriter};
+
+    pub(super) fn datapack(files: &[(&str, &str)]) -> Datapack {
+        let files = files
+            .iter()
+            .map(|(path, content)| (path.to_string(), content.as_bytes().to_vec()))
+            .collect();
+        Datapack { files }
+    }
+
+    #[test]
+    fn read_directory() -> Result<()> {
+        let datapack = Datapack::read(Path::new("packs/simple"))?;
+        let functions: Vec<String> = datapack.functions().collect();
+
+        assert_eq!(vec!["mctest:list", "mctest:plan", "mctest:test1", "mctest:test2"], functions);
+        assert_eq!(None, datapack.discover_tests()?);
+        Ok(())
+    }
+
+    #[test]
+    fn read_zip() -> Result<()> {
+        let dir = TempDir::new("mctest")?;
+        let path = dir.path().join("pack.zip");
+        let mut zip = ZipWriter::new(File::create(&path)?);
+        zip.start_file("pack.mcmeta", FileOptions::default())?;
+        zip.write_all(b"{}")?;
+        zip.add_directory("data/example/function/test", FileOptions::default())?;
+        zip.start_file("data/example/function/test/jump.mcfunction", FileOptions::default())?;
+        zip.write_all(b"say jump")?;
+        zip.finish()?;
+
+        let datapack = Datapack::read(&path)?;
+        assert_eq!(Some(vec!["example:test/jump".to_owned()]), datapack.discover_tests()?);
+        Ok(())
+    }
+
+    #[test]
+    fn discover_tests_by_convention() -> Result<()> {
+        let datapack = datapack(&[
+            ("data/example/functions/test/walk.mcfunction", ""),
+            ("data/example/functions/test/movement/jump.mcfunction", ""),
+            ("data/example/functions/helper.mcfunction", ""),
+            ("data/other/functions/test/swim.mcfunction", ""),
+        ]);
+
+        assert_eq!(
+            Some(vec![
+                "example:test/movement/jump".to_owned(),
+                "example:test/walk".to_owned(),
+                "other:test/swim".to_owned(),
+            ]),
+            datapack.discover_tests()?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn discover_benchmarks_by_convention() {
+        let datapack = datapack(&[
+            ("data/example/functions/bench/sort.mcfunction", ""),
+            ("data/example/functions/test/sort.mcfunction", ""),
+            ("data/other/function/bench/search.mcfunction", ""),
+        ]);
+
+        assert_eq!(vec!["example:bench/sort".to_owned(), "other:bench/search".to_owned()], datapack.discover_benchmarks());
+    }
+
+    #[test]
+    fn discover_tests_by_tag() -> Result<()> {
+        let datapack = datapack(&[
+            ("data/mctest/tags/functions/tests.json", r##"{ "values": ["example:b", "#example:more", { "id": "example:missing", "required": false }] }"##),
+            ("data/example/tags/functions/more.json", r#"{ "values": ["example:a"] }"#),
+            ("data/example/functions/test/ignored.mcfunction", ""),
+            ("data/example/functions/a.mcfunction", ""),
+            ("data/example/functions/b.mcfunction", ""),
+        ]);
+
+        assert_eq!(
+            Some(vec!["example:b".to_owned(), "example:a".to_owned()]),
+            datapack.discover_tests()?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn pack_formats_from_pack_mcmeta() -> Result<()> {
+        let format = datapack(&[("pack.mcmeta", r#"{ "pack": { "pack_format": 18 } }"#)]);
+        let range = datapack(&[("pack.mcmeta", r#"{ "pack": { "pack_format": 18, "supported_formats": [18, 26] } }"#)]);
+        let bounds = datapack(&[(
+            "pack.mcmeta",
+            r#"{ "pack": { "pack_format": 18, "supported_formats": { "min_inclusive": 15, "max_inclusive": 18 } } }"#,
+        )]);
+
+        assert_eq!(18..=18, format.pack_formats()?);
+        assert_eq!(18..=26, range.pack_formats()?);
+        assert_eq!(15..=18, bounds.pack_formats()?);
+        assert!(datapack(&[]).pack_formats().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn missing_required_tag_fails() {
+        let datapack = datapack(&[("data/mctest/tags/functions/tests.json", r##"{ "values": ["#example:missing"] }"##)]);
+
+        assert!(datapack.discover_tests().is_err());
+    }
+}
@@ -0,0 +1,182 @@
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::path::Path;
+
+use super::{function_paths, resource_id, Datapack};
+
+/// Objective counting how often each line of an instrumented pack ran, by the score holder `#<probe>`.
+pub const OBJECTIVE: &str = "mctest.coverage";
+/// Function of an instrumented pack that creates the objective when the pack loads.
+const SETUP: &str = "mctest:coverage";
+/// Function of an instrumented pack that copies the hits of every probe to [`HITS_STORAGE`] and resets them,
+/// so they can be read at once however many probes there are.
+pub const HITS: &str = "mctest:coverage/hits";
+/// Storage holding the hits copied by [`HITS`], as a list of ints `hits` indexed by probe.
+pub const HITS_STORAGE: &str = "mctest:coverage";
+
+/// The lines of the functions of a pack that can run, each counted by a probe of the instrumented pack.
+#[derive(Debug, Clone, Default)]
+pub struct Coverage {
+    /// Path within the pack and line number of each probe.
+    lines: Vec<(String, usize)>,
+}
+
+impl Datapack {
+    /// A copy of this pack whose functions count how often each of their commands runs, and
+    /// the lines it counts. Every command is preceded by a command adding to the score of its probe.
+    pub fn instrument(&self) -> (Datapack, Coverage) {
+        let mut files = self.files.clone();
+        let mut coverage = Coverage::default();
+        for (path, content) in &self.files {
+            if resource_id(path, "functions", ".mcfunction").is_none() {
+                continue;
+            }
+
+            let mut instrumented = String::new();
+            let mut continued = false;
+            for (index, line) in String::from_utf8_lossy(content).lines().enumerate() {
+                let command = line.trim();
+                // Commands continued over several lines with `\` are counted once, on their first line
+                if !continued && !command.is_empty() && !command.starts_with('#') {
+                    let probe = coverage.lines.len();
+                    writeln!(instrumented, "scoreboard players add #{probe} {OBJECTIVE} 1").ok();
+                    coverage.lines.push((path.clone(), index + 1));
+                }
+                writeln!(instrumented, "{line}").ok();
+                continued = command.ends_with('\\');
+            }
+            files.insert(path.clone(), instrumented.into_bytes());
+        }
+
+        // Probes that weren't hit have no score, getting it fails and stores 0
+        let probes = coverage.lines.len();
+        let mut hits = format!("data modify storage {HITS_STORAGE} hits set value [{}]\n", vec!["0"; probes].join(","));
+        for probe in 0..probes {
+            writeln!(
+                hits,
+                "execute store result storage {HITS_STORAGE} hits[{probe}] int 1 run scoreboard players get #{probe} {OBJECTIVE}"
+            )
+            .ok();
+        }
+        writeln!(hits, "scoreboard players reset * {OBJECTIVE}").ok();
+
+        // Runs before the load functions of the pack, so their commands are counted too
+        let setup = format!("scoreboard objectives add {OBJECTIVE} dummy\n");
+        let load = function_paths("data/minecraft/tags/functions/load.json")
+            .iter()
+            .find_map(|path| self.files.get(path))
+            .and_then(|content| serde_json::from_slice::<Value>(content).ok());
+        let mut load = load.unwrap_or_else(|| json!({ "values": [] }));
+        if let Some(values) = load["values"].as_array_mut() {
+            values.insert(0, SETUP.into());
+        }
+        let added = [
+            ("data/mctest/functions/coverage.mcfunction", setup),
+            ("data/mctest/functions/coverage/hits.mcfunction", hits),
+            ("data/minecraft/tags/functions/load.json", load.to_string()),
+        ];
+        for (path, content) in added {
+            for path in function_paths(path) {
+                files.insert(path, content.clone().into_bytes());
+            }
+        }
+
+        (Datapack { files }, coverage)
+    }
+}
+
+impl Coverage {
+    /// Number of probes, numbered from 0.
+    pub fn probes(&self) -> usize {
+        self.lines.len()
+    }
+
+    /// Describes how often each line ran, given the hits of each probe, in the LCOV format.
+    /// Files are given by their path within the pack at `root`, or by their path within the pack alone
+    /// if `root` is empty. Each function file is reported as a single function, which ran as often as
+    /// its first command did.
+    pub fn lcov(&self, hits: &[u64], root: &Path) -> String {
+        let mut files: BTreeMap<&str, Vec<(usize, u64)>> = BTreeMap::new();
+        for (probe, (path, line)) in self.lines.iter().enumerate() {
+            let hits = hits.get(probe).copied().unwrap_or(0);
+            files.entry(path).or_default().push((*line, hits));
+        }
+
+        let mut lcov = String::new();
+        for (path, lines) in files {
+            let function = resource_id(path, "functions", ".mcfunction").unwrap_or_else(|| path.to_owned());
+            let (first_line, calls) = lines[0];
+            writeln!(lcov, "TN:").ok();
+            writeln!(lcov, "SF:{}", root.join(path).display()).ok();
+            writeln!(lcov, "FN:{first_line},{function}").ok();
+            writeln!(lcov, "FNDA:{calls},{function}").ok();
+            writeln!(lcov, "FNF:1").ok();
+            writeln!(lcov, "FNH:{}", u8::from(calls > 0)).ok();
+            for (line, hits) in &lines {
+                writeln!(lcov, "DA:{line},{hits}").ok();
+            }
+            writeln!(lcov, "LF:{}", lines.len()).ok();
+            writeln!(lcov, "LH:{}", lines.iter().filter(|(_, hits)| *hits > 0).count()).ok();
+            writeln!(lcov, "end_of_record").ok();
+        }
+        lcov
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datapack::tests::datapack;
+
+    fn file(datapack: &Datapack, path: &str) -> String {
+        String::from_utf8(datapack.files[path].clone()).unwrap()
+    }
+
+    #[test]
+    fn instrument_counts_commands() {
+        let (instrumented, coverage) = datapack(&[
+            ("data/example/functions/test/walk.mcfunction", "# Walks\nsay walk\n\nexecute \\\n  as @a run say hi\n"),
+            ("data/example/functions/load.mcfunction", "say loaded\n"),
+            ("data/minecraft/tags/functions/load.json", r#"{"values":["example:load"]}"#),
+        ])
+        .instrument();
+
+        assert_eq!(
+            "# Walks\nscoreboard players add #1 mctest.coverage 1\nsay walk\n\n\
+             scoreboard players add #2 mctest.coverage 1\nexecute \\\n  as @a run say hi\n",
+            file(&instrumented, "data/example/functions/test/walk.mcfunction")
+        );
+        assert_eq!(
+            r#"{"values":["mctest:coverage","example:load"]}"#,
+            file(&instrumented, "data/minecraft/tags/function/load.json")
+        );
+        assert_eq!(3, coverage.probes());
+        assert_eq!(
+            "data modify storage mctest:coverage hits set value [0,0,0]\n\
+             execute store result storage mctest:coverage hits[0] int 1 run scoreboard players get #0 mctest.coverage\n\
+             execute store result storage mctest:coverage hits[1] int 1 run scoreboard players get #1 mctest.coverage\n\
+             execute store result storage mctest:coverage hits[2] int 1 run scoreboard players get #2 mctest.coverage\n\
+             scoreboard players reset * mctest.coverage\n",
+            file(&instrumented, "data/mctest/function/coverage/hits.mcfunction")
+        );
+    }
+
+    #[test]
+    fn lcov_reports_hits_per_line() {
+        let (_, coverage) = datapack(&[
+            ("data/example/functions/a.mcfunction", "say a\n# Never\nreturn 1\nsay unreachable\n"),
+            ("data/example/functions/b.mcfunction", "say b\n"),
+        ])
+        .instrument();
+
+        assert_eq!(
+            "TN:\nSF:pack/data/example/functions/a.mcfunction\nFN:1,example:a\nFNDA:2,example:a\nFNF:1\nFNH:1\n\
+             DA:1,2\nDA:3,2\nDA:4,0\nLF:3\nLH:2\nend_of_record\n\
+             TN:\nSF:pack/data/example/functions/b.mcfunction\nFN:1,example:b\nFNDA:0,example:b\nFNF:1\nFNH:0\n\
+             DA:1,0\nLF:1\nLH:0\nend_of_record\n",
+            coverage.lcov(&[2, 2], Path::new("pack"))
+        );
+        assert!(coverage.lcov(&[2, 2], Path::new("")).contains("SF:data/example/functions/b.mcfunction\n"));
+    }
+}
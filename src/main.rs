@@ -1,19 +1,161 @@
+mod datapack;
 mod minecraft_client;
 mod minecraft_server;
 mod test;
+mod version;
+mod watch;
 
-use std::path::PathBuf;
-use clap::Parser;
-use anyhow::Result;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::Duration;
+use clap::{Parser, Subcommand};
+use anyhow::{anyhow, Result};
 use uuid::Uuid;
 
-use minecraft_client::MinecraftClient;
-use minecraft_server::MinecraftServer;
-use test::run_tests;
+use datapack::Datapack;
+use minecraft_client::{ConnectionReadHalf, ConnectionWriteHalf, MinecraftClient};
+use minecraft_server::{MinecraftServer, RunningMinecraftServer};
+use test::{
+    report_benchmarks, run_benchmarks, run_matrix, run_parallel, run_tests, read_expectations, Baseline, BenchOptions, Expectations, Filter, Format, Hooks, Isolation,
+    Options, Reporter, Session, Snapshots, Summary,
+};
+use watch::Fingerprint;
+
+/// The version of Minecraft to run on unless told otherwise.
+const DEFAULT_VERSION: &str = "1.20.2";
 
 #[derive(Parser)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[command(flatten)]
+    args: Option<Args>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Measure how long the functions in `bench` directories of the datapack take to run, less the round trip
+    /// of a command to the server, which is reported first
+    Bench(BenchArgs),
+}
+
+/// Runs the tests of a datapack
+#[derive(clap::Args)]
 struct Args {
-    datapack_path: PathBuf
+    datapack_path: PathBuf,
+    /// Format of the test report
+    #[arg(long, value_enum, default_value = "tap")]
+    format: Format,
+    /// Seconds to wait for each test before failing it
+    #[arg(long, value_parser = parse_seconds, default_value = "30")]
+    timeout: Duration,
+    /// Seconds the whole test run may take
+    #[arg(long, value_parser = parse_seconds)]
+    global_timeout: Option<Duration>,
+    /// Only run tests whose function id contains the name or matches the glob
+    #[arg(long, value_name = "PATTERN")]
+    filter: Vec<String>,
+    /// Skip tests whose function id contains the name or matches the glob
+    #[arg(long, value_name = "PATTERN")]
+    exclude: Vec<String>,
+    /// Restore the world between tests so they can't affect each other, by restarting the server
+    #[arg(long)]
+    isolate: bool,
+    /// Number of servers to share the tests between
+    #[arg(long, value_name = "N", default_value = "1", value_parser = clap::value_parser!(u16).range(1..))]
+    jobs: u16,
+    /// Times to run a failing test again before reporting it as failed
+    #[arg(long, value_name = "N", default_value = "0")]
+    retries: usize,
+    /// Run the tests in random order
+    #[arg(long)]
+    shuffle: bool,
+    /// Seed to shuffle the tests with, to reproduce an earlier order
+    #[arg(long, value_name = "N", requires = "shuffle")]
+    seed: Option<u64>,
+    /// Keep the server running and run the tests again whenever the datapack changes
+    #[arg(long, conflicts_with = "jobs")]
+    watch: bool,
+    /// Compare the output of each test to a snapshot kept in `__snapshots__` next to the datapack. Tests
+    /// without a snapshot yet fail after one is written, unless snapshots are being updated
+    #[arg(long)]
+    snapshots: bool,
+    /// Replace snapshots that don't match and write missing ones instead of failing their tests
+    #[arg(long)]
+    update_snapshots: bool,
+    /// Include the storage in snapshots
+    #[arg(long, value_name = "ID")]
+    snapshot_storage: Vec<String>,
+    /// Include the score in snapshots, eg. `#counter vars`
+    #[arg(long, value_name = "TARGET OBJECTIVE")]
+    snapshot_score: Vec<String>,
+    /// Check the world after each test against the expectations in the YAML file, which lists them by test
+    /// function id, eg. `example:test/jump: [{ score: "#jumps vars", equals: 1 }]`
+    #[arg(long, value_name = "FILE")]
+    expect: Option<PathBuf>,
+    /// Count how often each line of the functions of the datapack runs and write an LCOV report of it.
+    /// Files of zipped datapacks are reported by their path within the archive
+    #[arg(long, value_name = "FILE", num_args = 0..=1, default_missing_value = "lcov.info", conflicts_with = "watch")]
+    coverage: Option<PathBuf>,
+    /// Versions of Minecraft to run the tests on, eg. `1.20.2` or a range like `1.20.1..1.20.4`. Besides
+    /// version ids, `latest` and `latest-snapshot` name the latest release and snapshot, and `auto` the newest
+    /// release supporting the data pack format of the datapack. Only 1.20, 1.20.1 and 1.20.2 can be connected
+    /// to so far, other versions are rejected before any server is downloaded. The assertion functions need
+    /// function macros, which came with 1.20.2
+    #[arg(long = "version", value_name = "VERSION", value_delimiter = ',', default_value = DEFAULT_VERSION)]
+    versions: Vec<String>,
+}
+
+#[derive(clap::Args)]
+struct BenchArgs {
+    datapack_path: PathBuf,
+    /// Version of Minecraft to run the functions on, or `latest`, `latest-snapshot` or `auto` like when testing
+    #[arg(long, default_value = DEFAULT_VERSION)]
+    version: String,
+    /// Seconds to wait for each run of a function before failing
+    #[arg(long, value_parser = parse_seconds, default_value = "30")]
+    timeout: Duration,
+    /// Only measure functions whose id contains the name or matches the glob
+    #[arg(long, value_name = "PATTERN")]
+    filter: Vec<String>,
+    /// Skip functions whose id contains the name or matches the glob
+    #[arg(long, value_name = "PATTERN")]
+    exclude: Vec<String>,
+    /// Times to run each function before measuring
+    #[arg(long, value_name = "N", default_value = "10")]
+    warmup: usize,
+    /// Times to run each function while measuring
+    #[arg(long, value_name = "N", default_value = "100", value_parser = clap::value_parser!(u32).range(1..))]
+    iterations: u32,
+    /// Compare the results to those saved earlier with `--save-baseline`
+    #[arg(long, value_name = "FILE")]
+    baseline: Option<PathBuf>,
+    /// Save the results to compare later runs to
+    #[arg(long, value_name = "FILE")]
+    save_baseline: Option<PathBuf>,
+}
+
+fn parse_seconds(s: &str) -> Result<Duration> {
+    Ok(Duration::try_from_secs_f64(s.parse()?)?)
+}
+
+/// Isolates tests by restarting the server from a copy of its world.
+struct Restarting<'a> {
+    server: &'a mut RunningMinecraftServer,
+    client: &'a MinecraftClient,
+}
+
+impl Isolation<ConnectionReadHalf, ConnectionWriteHalf> for Restarting<'_> {
+    fn snapshot(&mut self) -> Result<()> {
+        self.server.snapshot()
+    }
+
+    fn restore(&mut self) -> Result<(ConnectionReadHalf, ConnectionWriteHalf)> {
+        self.server.restore()?;
+        Ok(self.client.connect_to(self.server)?.split())
+    }
 }
 
 // Java incorrectly builds V3 uuids by ignoring the need for a namespace.
@@ -26,16 +168,221 @@ fn offline_player_uuid(name: &str) -> Uuid {
     Uuid::from_bytes(hash)
 }
 
-fn main() -> Result<()> {
-    let Args { datapack_path } = Args::parse();
+/// Starts `server` and runs the tests on it with a client of the given `uuid`.
+fn run_on(server: MinecraftServer, uuid: Uuid, reporter: &mut dyn Reporter, options: Options, isolate: bool) -> Result<Summary> {
+    let mut server = server.start()?;
+    reporter.server_ready()?;
+    let client = MinecraftClient::new("player", uuid);
+    let (reader, writer) = client.connect_to(&server)?.split();
+    let mut session = Session::new(reader, writer);
+    run_session(&mut server, &client, &mut session, reporter, options, isolate)
+}
+
+fn run_session(
+    server: &mut RunningMinecraftServer,
+    client: &MinecraftClient,
+    session: &mut Session,
+    reporter: &mut dyn Reporter,
+    options: Options,
+    isolate: bool,
+) -> Result<Summary> {
+    let mut restarting = Restarting { server, client };
+    let isolation = isolate.then_some(&mut restarting as &mut dyn Isolation<_, _>);
+    run_tests(session, reporter, options, isolation)
+}
+
+/// Runs the tests whenever the datapack changes, on the same server and client throughout.
+/// Each run is reported by a new reporter from `new_reporter`, and runs that fail don't stop the watching.
+fn run_watching(
+    server: MinecraftServer,
+    uuid: Uuid,
+    mut reporter: Box<dyn Reporter>,
+    new_reporter: impl Fn() -> Box<dyn Reporter>,
+    mut options: Options,
+    isolate: bool,
+    datapack_path: &Path,
+) -> Result<()> {
+    let mut fingerprint = Fingerprint::of(datapack_path)?;
+    let mut server = server.start()?;
+    reporter.server_ready()?;
+    let client = MinecraftClient::new("player", uuid);
+    let (reader, writer) = client.connect_to(&server)?.split();
+    let mut session = Session::new(reader, writer);
+
+    let mut first = true;
+    watch::repeat(
+        || {
+            if !first {
+                reporter = new_reporter();
+                server.update_datapack(datapack_path)?;
+                // Tests may have been added or removed
+                let datapack = Datapack::read(datapack_path)?;
+                options = Options {
+                    tests: datapack.discover_tests()?,
+                    hooks: Hooks::find(|id| datapack.has_function(id)),
+                    reload: true,
+                    ..options.clone()
+                };
+            }
+            first = false;
+            run_session(&mut server, &client, &mut session, reporter.as_mut(), options.clone(), isolate)?;
+            Ok(())
+        },
+        || {
+            eprintln!("Watching {} for changes", datapack_path.display());
+            fingerprint = watch::wait_for_change(datapack_path, &fingerprint)?;
+            Ok(())
+        },
+    )
+}
+
+fn main() -> Result<ExitCode> {
+    let cli = Cli::parse();
+    match (cli.command, cli.args) {
+        (Some(Command::Bench(args)), _) => bench(args),
+        (None, Some(args)) => test(args),
+        (None, None) => Err(anyhow!("No datapack given")),
+    }
+}
+
+/// Measures the benchmarks of a datapack on a fresh server, printing how long they took.
+fn bench(args: BenchArgs) -> Result<ExitCode> {
+    let BenchArgs { datapack_path, version, timeout, filter, exclude, warmup, iterations, baseline, save_baseline } = args;
+    let filter = Filter::new(&filter, &exclude)?;
+    let datapack = Datapack::read(&datapack_path)?;
+    let functions: Vec<String> = datapack
+        .discover_benchmarks()
+        .into_iter()
+        .filter(|id| filter.matches(&format!("/function {id}")))
+        .collect();
+    if functions.is_empty() {
+        return Err(anyhow!("No benchmarks found in {}", datapack_path.display()));
+    }
+    let baseline: Option<Baseline> = baseline.map(|path| Ok::<_, anyhow::Error>(serde_json::from_slice(&fs::read(path)?)?)).transpose()?;
+
+    let [version] = version::resolve(&[version], &datapack)?.try_into().map_err(|_| anyhow!("Benchmarks run on a single version"))?;
     let uuid = offline_player_uuid("player");
-    let server = MinecraftServer::new("1.20.2", uuid, &datapack_path)?;
-    let server = server.start()?;
+    let server = MinecraftServer::new(&version, uuid, &datapack_path, None)?.start()?;
     let client = MinecraftClient::new("player", uuid);
     let (reader, writer) = client.connect_to(&server)?.split();
-    
-    println!("TAP version 14");
-    run_tests(reader, writer)?;
+    let options = BenchOptions { warmup, iterations: iterations as usize, timeout: Some(timeout) };
+    let results = run_benchmarks(&mut Session::new(reader, writer), &functions, &options)?;
+
+    print!("{}", report_benchmarks(&results, baseline.as_ref()));
+    if let Some(path) = save_baseline {
+        let results: Baseline = results.into_iter().collect();
+        fs::write(path, serde_json::to_string_pretty(&results)?)?;
+    }
+    Ok(ExitCode::SUCCESS)
+}
 
-    Ok(())
-}
\ No newline at end of file
+/// Runs the tests of a datapack, reporting the results. Fails if any test does.
+fn test(args: Args) -> Result<ExitCode> {
+    let Args {
+        datapack_path,
+        format,
+        timeout,
+        global_timeout,
+        filter,
+        exclude,
+        isolate,
+        jobs,
+        retries,
+        shuffle,
+        seed,
+        watch,
+        snapshots,
+        update_snapshots,
+        snapshot_storage,
+        snapshot_score,
+        expect,
+        coverage: coverage_path,
+        versions,
+    } = args;
+    let filter = Filter::new(&filter, &exclude)?;
+    let name = datapack_path.file_stem().unwrap_or_default().to_string_lossy();
+    let datapack = Datapack::read(&datapack_path)?;
+    let tests = datapack.discover_tests()?;
+    let hooks = Hooks::find(|id| datapack.has_function(id));
+    let mut reporter = format.reporter(&name);
+    // Kept beside the datapack rather than in it, so updating them isn't a change to watch for
+    let snapshots = (snapshots || update_snapshots).then(|| Snapshots {
+        dir: datapack_path.with_file_name("__snapshots__").join(name.as_ref()),
+        update: update_snapshots,
+        storages: snapshot_storage,
+        scores: snapshot_score,
+    });
+    // Packs listing their own tests can only be checked for having the function
+    let expectations: Expectations = expect
+        .map(|path| {
+            read_expectations(&path, |id| match &tests {
+                Some(tests) => tests.iter().any(|test| test == id),
+                None => datapack.has_function(id),
+            })
+        })
+        .transpose()?
+        .unwrap_or_default();
+
+    let versions = version::resolve(&versions, &datapack)?;
+    let uuid = offline_player_uuid("player");
+    let (instrumented, coverage) = match coverage_path {
+        Some(_) => {
+            let (instrumented, coverage) = datapack.instrument();
+            (Some(instrumented), Some(coverage))
+        }
+        None => (None, None),
+    };
+
+    let options = Options {
+        timeout: Some(timeout),
+        global_timeout,
+        tests,
+        filter,
+        hooks,
+        shard: None,
+        cancelled: Default::default(),
+        retries,
+        shuffle: shuffle.then(|| seed.unwrap_or_else(rand::random)),
+        reload: false,
+        snapshots,
+        expectations,
+        coverage: coverage.as_ref().map(|coverage| coverage.probes()),
+    };
+    if watch {
+        let [version] = versions.as_slice() else {
+            return Err(anyhow!("Only a single version can be watched"));
+        };
+        reporter.server_starting(version)?;
+        let server = MinecraftServer::new(version, uuid, &datapack_path, instrumented.as_ref())?;
+        let new_reporter = || format.reporter(&name);
+        return run_watching(server, uuid, reporter, new_reporter, options, isolate, &datapack_path).map(|()| ExitCode::SUCCESS);
+    }
+    let run_version = |version: &str, reporter: &mut dyn Reporter| -> Result<Summary> {
+        reporter.server_starting(version)?;
+        let server = MinecraftServer::new(version, uuid, &datapack_path, instrumented.as_ref())?;
+        if jobs > 1 {
+            run_parallel(jobs.into(), reporter, options.clone(), |options, reporter| {
+                run_on(server.duplicate()?, uuid, reporter, options, isolate)
+            })
+        } else {
+            run_on(server, uuid, reporter, options.clone(), isolate)
+        }
+    };
+    // Runs on several versions are grouped by version
+    let summary = match versions.as_slice() {
+        [version] => run_version(version, reporter.as_mut())?,
+        versions => run_matrix(versions, reporter.as_mut(), run_version)?,
+    };
+
+    if let (Some(path), Some(coverage)) = (coverage_path, coverage) {
+        // Files in a zip can't be pointed to, so they're given relative to the root of the pack instead
+        let root = if datapack_path.is_dir() { datapack_path.as_path() } else { Path::new("") };
+        fs::write(path, coverage.lcov(&summary.hits, root))?;
+    }
+
+    if summary.success() {
+        Ok(ExitCode::SUCCESS)
+    } else {
+        Ok(ExitCode::FAILURE)
+    }
+}
@@ -2,11 +2,17 @@ mod mcp;
 
 use anyhow::Result;
 use mcp::McpConnection;
+
+pub use mcp::{PROTOCOL_1_20_1, PROTOCOL_1_20_2};
+// The fake server of the runner's tests turns away commands the client couldn't send
+#[cfg(test)]
+pub use mcp::MAX_COMMAND_LENGTH;
 use std::collections::VecDeque;
 use std::io::{self, Read, Write};
 use std::mem;
-use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender, TryRecvError};
 use std::sync::Arc;
+use std::time::Duration;
 use uuid::Uuid;
 
 use crate::minecraft_server::RunningMinecraftServer;
@@ -28,6 +34,7 @@ impl MinecraftClient {
 
         let mcp_connection = mcp::connect(
             port,
+            server.protocol(),
             self.name.clone(),
             self.uuid,
             server_chat_text_sender,
@@ -84,7 +91,7 @@ impl Write for ConnectionWriteHalf {
             return Ok(());
         }
 
-        let leftover = mem::replace(&mut self.write_buffer, String::new());
+        let leftover = mem::take(&mut self.write_buffer);
         self.chat_text_sender
             .send(leftover)
             .map_err(|_| io::ErrorKind::ConnectionReset.into())
@@ -93,14 +100,28 @@ impl Write for ConnectionWriteHalf {
 
 pub struct ConnectionReadHalf {
     chat_text_receiver: Receiver<String>,
+    read_timeout: Option<Duration>,
     _mcp_connection: Arc<dyn McpConnection>,
 }
 
 impl ConnectionReadHalf {
+    /// Sets how long reads wait for a message before failing with [`io::ErrorKind::TimedOut`].
+    /// `None` waits indefinitely.
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.read_timeout = timeout;
+    }
+
     fn receive(&self) -> io::Result<String> {
-        self.chat_text_receiver
-            .recv()
-            .map_err(|_| io::ErrorKind::ConnectionReset.into())
+        match self.read_timeout {
+            Some(timeout) => self.chat_text_receiver.recv_timeout(timeout).map_err(|e| match e {
+                RecvTimeoutError::Timeout => io::ErrorKind::TimedOut.into(),
+                RecvTimeoutError::Disconnected => io::ErrorKind::ConnectionReset.into(),
+            }),
+            None => self
+                .chat_text_receiver
+                .recv()
+                .map_err(|_| io::ErrorKind::ConnectionReset.into()),
+        }
     }
 
     fn try_receive(&self) -> io::Result<Option<String>> {
@@ -132,6 +153,7 @@ impl Connection {
         let mcp_connection: Arc<dyn McpConnection> = Arc::from(self._mcp_connection);
         let read_half = ConnectionReadHalf {
             chat_text_receiver: self.chat_text_receiver,
+            read_timeout: None,
             _mcp_connection: mcp_connection.clone(),
         };
         let write_half = ConnectionWriteHalf {
@@ -219,7 +241,7 @@ impl Write for Connection {
             return Ok(());
         }
 
-        let leftover = mem::replace(&mut self.write_buffer, String::new());
+        let leftover = mem::take(&mut self.write_buffer);
         self.chat_text_sender
             .send(leftover)
             .map_err(|_| io::ErrorKind::ConnectionReset.into())
@@ -249,6 +271,24 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn connection_read_timeout() -> Result<()> {
+        let (sender, receiver) = channel();
+        let (mut read_half, _) = Connection::new(channel().0, receiver, Box::new(McpDummy)).split();
+        read_half.set_read_timeout(Some(Duration::from_millis(10)));
+        let mut con = BufReader::new(read_half);
+
+        let mut buf = String::new();
+        let error = con.read_line(&mut buf).unwrap_err();
+        assert_eq!(io::ErrorKind::TimedOut, error.kind());
+
+        sender.send("Hello, world".to_string())?;
+        con.read_line(&mut buf)?;
+        assert_eq!("Hello, world\n", buf);
+
+        Ok(())
+    }
+
     #[test]
     fn connection_write() -> Result<()> {
         let (sender, receiver) = channel();
@@ -4,7 +4,6 @@ use anyhow::{anyhow, Result};
 use chrono::Local;
 use mc_varint::{VarInt, VarIntRead, VarIntWrite};
 use parking_lot::{Mutex, RwLock};
-use rand;
 use std::thread;
 use std::{
     io::{Cursor, Read, Write},
@@ -16,19 +15,33 @@ use std::{
 };
 use uuid::Uuid;
 
+/// The version of the network protocol of Minecraft 1.20 and 1.20.1.
+pub const PROTOCOL_1_20_1: i32 = 763;
+/// The version of the network protocol of Minecraft 1.20.2, which added the configuration state after login.
+pub const PROTOCOL_1_20_2: i32 = 764;
+
+/// Commands sent to the server, without their leading `/`, must be shorter than this many bytes.
+pub const MAX_COMMAND_LENGTH: usize = 256;
+
 pub trait McpConnection {}
 
-impl McpConnection for Mcp764Connection {}
+impl McpConnection for RunningMcp {}
 
+/// Connects to the server listening on `port`, speaking the network protocol version `protocol`.
 pub fn connect(
     port: u16,
+    protocol: i32,
     player_name: String,
     player_uuid: Uuid,
     chat_text_sender: Sender<String>,
     chat_text_receiver: Receiver<String>,
 ) -> Result<Box<dyn McpConnection>> {
-    Ok(Box::new(Mcp764Connection::connect(
+    if ![PROTOCOL_1_20_1, PROTOCOL_1_20_2].contains(&protocol) {
+        return Err(anyhow!("Unsupported protocol version {protocol}"));
+    }
+    Ok(Box::new(RunningMcp::connect(
         port,
+        protocol,
         player_name,
         player_uuid,
         chat_text_sender,
@@ -36,20 +49,22 @@ pub fn connect(
     )?))
 }
 
-struct Mcp764Connection {
+struct RunningMcp {
     run: Arc<RwLock<bool>>,
 }
 
-impl Mcp764Connection {
+impl RunningMcp {
     fn connect(
         port: u16,
+        protocol: i32,
         player_name: String,
         player_uuid: Uuid,
         chat_text_sender: Sender<String>,
         chat_text_receiver: Receiver<String>,
     ) -> Result<Self> {
         let tcp_stream = TcpStream::connect(("localhost", port))?;
-        let mcp = Mcp764 {
+        let mcp = Mcp {
+            protocol,
             state: Mutex::new(State::Handshaking),
             tcp_stream: Mutex::new(tcp_stream),
         };
@@ -60,15 +75,16 @@ impl Mcp764Connection {
     }
 }
 
-struct Mcp764 {
+struct Mcp {
+    protocol: i32,
     state: Mutex<State>,
     tcp_stream: Mutex<TcpStream>,
 }
 
-impl Mcp764 {
+impl Mcp {
     fn login(&self, port: u16, player_name: String, player_uuid: Uuid) -> Result<()> {
         self.write_packet(ServerBoundPacket::Handshake {
-            protocol_version: VarInt::from(764),
+            protocol_version: VarInt::from(self.protocol),
             server_address: MinecraftString::try_from("localhost".to_owned())?,
             server_port: port,
             next_state: State::Login,
@@ -78,7 +94,12 @@ impl Mcp764 {
             player_uuid,
         })?;
         if let ClientBoundPacket::LoginSuccess = self.read_packet()? {
-            self.write_packet(ServerBoundPacket::LoginAcknowledged)?;
+            if self.protocol >= PROTOCOL_1_20_2 {
+                self.write_packet(ServerBoundPacket::LoginAcknowledged)?;
+            } else {
+                // Older servers go straight from login to play
+                *self.state.lock() = State::Play;
+            }
             // println!("Login success!");
         } else {
             return Err(anyhow!("Login failed."));
@@ -88,16 +109,19 @@ impl Mcp764 {
     }
 
     fn configure(&self) -> Result<()> {
-        loop {
-            match self.read_packet()? {
-                ClientBoundPacket::Disconnect { reason } => return Err(anyhow!("Disconnected by server. {}", reason.into_inner())),
-                ClientBoundPacket::FinishConfiguration => {
-                    break;
-                }
-                ClientBoundPacket::KeepAlive { id } => {
-                    self.write_packet(ServerBoundPacket::KeepAlive { id })?
+        // Older servers have no configuration state, the client information is sent in play instead
+        if self.protocol >= PROTOCOL_1_20_2 {
+            loop {
+                match self.read_packet()? {
+                    ClientBoundPacket::Disconnect { reason } => return Err(anyhow!("Disconnected by server. {}", reason.into_inner())),
+                    ClientBoundPacket::FinishConfiguration => {
+                        break;
+                    }
+                    ClientBoundPacket::KeepAlive { id } => {
+                        self.write_packet(ServerBoundPacket::KeepAlive { id })?
+                    }
+                    _ => {}
                 }
-                _ => {}
             }
         }
 
@@ -111,7 +135,9 @@ impl Mcp764 {
             enable_text_filtering: false,
             allow_server_listings: true,
         })?;
-        self.write_packet(ServerBoundPacket::FinishConfiguration)?;
+        if self.protocol >= PROTOCOL_1_20_2 {
+            self.write_packet(ServerBoundPacket::FinishConfiguration)?;
+        }
 
         // println!("Configuration success!");
 
@@ -122,7 +148,7 @@ impl Mcp764 {
         self,
         chat_text_sender: Sender<String>,
         chat_text_receiver: Receiver<String>,
-    ) -> Result<Mcp764Connection> {
+    ) -> Result<RunningMcp> {
         let mcp = Arc::new(Mutex::new(self));
         let run = Arc::new(RwLock::new(true));
 
@@ -174,10 +200,8 @@ impl Mcp764 {
 
                             chat_text_sender.send(content.into_inner())?;
                         }
-                        ClientBoundPacket::Unknown { packet_id, .. } => {
-                            if [0x1C, 0x67].contains(&packet_id) {
-                                println!("{:?}", packet);
-                            }
+                        ClientBoundPacket::Unknown { packet_id, .. } if [0x1C, 0x67].contains(&packet_id) => {
+                            eprintln!("{:?}", packet);
                         }
                         _ => {}
                     }
@@ -192,10 +216,10 @@ impl Mcp764 {
             thread::spawn(move || {
                 while *run.read() {
                     let text = chat_text_receiver.recv()?;
-                    if text.starts_with("/") {
+                    if let Some(command) = text.strip_prefix('/') {
                         let mcp = mcp.lock();
                         mcp.write_packet(ServerBoundPacket::ChatCommand {
-                            command: MinecraftString::try_from(text[1..].to_owned())?,
+                            command: MinecraftString::try_from(command.to_owned())?,
                             timestamp: Local::now().timestamp_millis(),
                             salt: rand::random(),
                             message_count: VarInt::from(0),
@@ -218,7 +242,7 @@ impl Mcp764 {
             });
         }
 
-        Ok(Mcp764Connection { run })
+        Ok(RunningMcp { run })
     }
 
     fn write_packet(&self, packet: ServerBoundPacket) -> Result<()> {
@@ -227,12 +251,12 @@ impl Mcp764 {
 
         let mut state = self.state.lock();
 
-        buffer.write_var_int(packet.packet_id(*state))?;
-        buffer.write(&packet.payload()?)?;
+        buffer.write_var_int(packet.packet_id(*state, self.protocol))?;
+        buffer.write_all(&packet.payload(self.protocol)?)?;
 
         let mut tcp_stream = self.tcp_stream.lock();
         tcp_stream.write_var_int(VarInt::from(buffer.len() as i32))?;
-        tcp_stream.write(&buffer)?;
+        tcp_stream.write_all(&buffer)?;
 
         *state = change_state(*state);
 
@@ -249,11 +273,12 @@ impl Mcp764 {
         let mut payload = Vec::new();
         content.read_to_end(&mut payload)?;
 
-        ClientBoundPacket::from(*self.state.lock(), packet_id, &payload)
+        ClientBoundPacket::from(*self.state.lock(), self.protocol, packet_id, &payload)
     }
 }
 
 #[derive(Debug)]
+#[allow(clippy::large_enum_variant)]
 enum ClientBoundPacket {
     Unknown { packet_id: i32 },
     LoginSuccess,
@@ -290,21 +315,36 @@ struct PlayerChatPreviousMessages {
     total_previous_message: VarInt
 }
 
+/// Ids of the clientbound packets of the play state, which moved between protocol versions.
+struct PlayPacketIds {
+    disconnect: i32,
+    keep_alive: i32,
+    player_chat_message: i32,
+    system_chat_message: i32,
+}
+
+impl PlayPacketIds {
+    fn of(protocol: i32) -> Self {
+        if protocol >= PROTOCOL_1_20_2 {
+            PlayPacketIds { disconnect: 0x1B, keep_alive: 0x24, player_chat_message: 0x37, system_chat_message: 0x67 }
+        } else {
+            PlayPacketIds { disconnect: 0x1A, keep_alive: 0x23, player_chat_message: 0x35, system_chat_message: 0x64 }
+        }
+    }
+}
+
 impl ClientBoundPacket {
-    fn from(state: State, packet_id: VarInt, payload: &[u8]) -> Result<ClientBoundPacket> {
+    fn from(state: State, protocol: i32, packet_id: VarInt, payload: &[u8]) -> Result<ClientBoundPacket> {
         let mut payload = Cursor::new(payload);
+        let play = PlayPacketIds::of(protocol);
         match (state, i32::from(packet_id)) {
-            (State::Login, 0x00) | (State::Configuration, 0x01) | (State::Play, 0x1B) => {
-                let reason = payload.read_minecraft_string()?;
-                Ok(ClientBoundPacket::Disconnect { reason } )
-            }
+            (State::Login, 0x00) | (State::Configuration, 0x01) => Self::disconnect(&mut payload),
+            (State::Play, id) if id == play.disconnect => Self::disconnect(&mut payload),
             (State::Login, 0x02) => Ok(ClientBoundPacket::LoginSuccess),
             (State::Configuration, 0x02) => Ok(ClientBoundPacket::FinishConfiguration),
-            (State::Configuration, 0x03) | (State::Play, 0x24) => {
-                let id = payload.read_long()?;
-                Ok(ClientBoundPacket::KeepAlive { id })
-            }
-            (State::Play, 0x37) => {
+            (State::Configuration, 0x03) => Self::keep_alive(&mut payload),
+            (State::Play, id) if id == play.keep_alive => Self::keep_alive(&mut payload),
+            (State::Play, id) if id == play.player_chat_message => {
                 let sender = payload.read_uuid()?;
                 let index = payload.read_var_int()?;
                 let message_signature = if payload.read_bool()? {
@@ -333,7 +373,7 @@ impl ClientBoundPacket {
                     previous_messages: PlayerChatPreviousMessages { total_previous_message }
                 }))
             }
-            (State::Play, 0x67) => {
+            (State::Play, id) if id == play.system_chat_message => {
                 let content = payload.read_minecraft_string()?;
 
                 Ok(ClientBoundPacket::SystemChatMessage { content })
@@ -341,9 +381,20 @@ impl ClientBoundPacket {
             (_, packet_id) => Ok(ClientBoundPacket::Unknown { packet_id }),
         }
     }
+
+    fn disconnect(payload: &mut impl Read) -> Result<ClientBoundPacket> {
+        let reason = payload.read_minecraft_string()?;
+        Ok(ClientBoundPacket::Disconnect { reason })
+    }
+
+    fn keep_alive(payload: &mut impl Read) -> Result<ClientBoundPacket> {
+        let id = payload.read_long()?;
+        Ok(ClientBoundPacket::KeepAlive { id })
+    }
 }
 
 #[derive(Debug)]
+#[allow(clippy::large_enum_variant)]
 enum ServerBoundPacket {
     Handshake {
         protocol_version: VarInt,
@@ -379,7 +430,7 @@ enum ServerBoundPacket {
         acknowledged: [UByte; 3],
     },
     ChatCommand {
-        command: MinecraftString<256>,
+        command: MinecraftString<MAX_COMMAND_LENGTH>,
         timestamp: Long,
         salt: Long,
         message_count: VarInt,
@@ -391,20 +442,21 @@ enum ServerBoundPacket {
 }
 
 impl ServerBoundPacket {
-    fn packet_id(&self, state: State) -> VarInt {
+    fn packet_id(&self, state: State, protocol: i32) -> VarInt {
+        let since_1_20_2 = protocol >= PROTOCOL_1_20_2;
         match self {
             ServerBoundPacket::Handshake { .. } => VarInt::from(0x00),
             ServerBoundPacket::LoginStart { .. } => VarInt::from(0x00),
             ServerBoundPacket::LoginAcknowledged => VarInt::from(0x03),
             ServerBoundPacket::KeepAlive { .. } => match state {
                 State::Configuration => VarInt::from(0x03),
-                State::Play => VarInt::from(0x14),
+                State::Play => VarInt::from(if since_1_20_2 { 0x14 } else { 0x12 }),
                 _ => unimplemented!(),
             },
             ServerBoundPacket::FinishConfiguration => VarInt::from(0x02),
             ServerBoundPacket::ClientInformation { .. } => match state {
                 State::Configuration => VarInt::from(0x00),
-                State::Play => VarInt::from(0x09),
+                State::Play => VarInt::from(if since_1_20_2 { 0x09 } else { 0x08 }),
                 _ => unimplemented!(),
             },
             ServerBoundPacket::ChatMessage { .. } => VarInt::from(0x05),
@@ -413,7 +465,7 @@ impl ServerBoundPacket {
         }
     }
 
-    fn payload(self) -> Result<Vec<u8>> {
+    fn payload(self, protocol: i32) -> Result<Vec<u8>> {
         let mut buffer = Vec::new();
         match self {
             ServerBoundPacket::Handshake {
@@ -429,6 +481,10 @@ impl ServerBoundPacket {
             }
             ServerBoundPacket::LoginStart { name, player_uuid } => {
                 buffer.write_minecraft_string(&name)?;
+                // The UUID used to be optional
+                if protocol < PROTOCOL_1_20_2 {
+                    buffer.write_bool(true)?;
+                }
                 buffer.write_uuid(player_uuid)?;
             }
             ServerBoundPacket::LoginAcknowledged => {}
@@ -536,12 +592,12 @@ trait WriteExt {
 
 impl<W: Write> WriteExt for W {
     fn write_byte(&mut self, value: i8) -> Result<()> {
-        self.write(&value.to_be_bytes())?;
+        self.write_all(&value.to_be_bytes())?;
         Ok(())
     }
 
     fn write_ubyte(&mut self, value: u8) -> Result<()> {
-        self.write(&value.to_be_bytes())?;
+        self.write_all(&value.to_be_bytes())?;
         Ok(())
     }
 
@@ -550,12 +606,12 @@ impl<W: Write> WriteExt for W {
     }
 
     fn write_ushort(&mut self, value: UShort) -> Result<()> {
-        self.write(&value.to_be_bytes())?;
+        self.write_all(&value.to_be_bytes())?;
         Ok(())
     }
 
     fn write_long(&mut self, value: Long) -> Result<()> {
-        self.write(&value.to_be_bytes())?;
+        self.write_all(&value.to_be_bytes())?;
         Ok(())
     }
 
@@ -564,7 +620,7 @@ impl<W: Write> WriteExt for W {
         value: &MinecraftString<MAX_LENGTH>,
     ) -> Result<()> {
         self.write_var_int(VarInt::from(value.0.len() as i32))?;
-        self.write(value.0.as_bytes())?;
+        self.write_all(value.0.as_bytes())?;
         Ok(())
     }
 
@@ -579,7 +635,7 @@ impl<W: Write> WriteExt for W {
     }
 
     fn write_uuid(&mut self, value: Uuid) -> Result<()> {
-        self.write(&value.as_u128().to_be_bytes())?;
+        self.write_all(&value.as_u128().to_be_bytes())?;
         Ok(())
     }
 }
@@ -649,7 +705,7 @@ impl<const MAX_LENGTH: usize> TryFrom<String> for MinecraftString<MAX_LENGTH> {
             ));
         }
 
-        Ok(MinecraftString::<MAX_LENGTH>(inner.into()))
+        Ok(MinecraftString::<MAX_LENGTH>(inner))
     }
 
     type Error = anyhow::Error;
@@ -659,3 +715,33 @@ type UShort = u16;
 type Long = i64;
 type Byte = i8;
 type UByte = u8;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn play_packets_are_read_by_protocol() -> Result<()> {
+        let mut payload = Vec::new();
+        payload.write_minecraft_string(&MinecraftString::<262144>::try_from("hi".to_owned())?)?;
+        let read = |protocol: i32, packet_id: i32| ClientBoundPacket::from(State::Play, protocol, VarInt::from(packet_id), &payload);
+
+        assert!(matches!(read(PROTOCOL_1_20_1, 0x64)?, ClientBoundPacket::SystemChatMessage { content } if content.0 == "hi"));
+        assert!(matches!(read(PROTOCOL_1_20_2, 0x67)?, ClientBoundPacket::SystemChatMessage { content } if content.0 == "hi"));
+        assert!(matches!(read(PROTOCOL_1_20_1, 0x1B)?, ClientBoundPacket::Unknown { packet_id: 0x1B }));
+        assert!(matches!(read(PROTOCOL_1_20_2, 0x1B)?, ClientBoundPacket::Disconnect { .. }));
+        Ok(())
+    }
+
+    #[test]
+    fn login_start_has_optional_uuid_before_1_20_2() -> Result<()> {
+        let login_start = || ServerBoundPacket::LoginStart {
+            name: MinecraftString::try_from("a".to_owned()).unwrap(),
+            player_uuid: Uuid::nil(),
+        };
+        assert_eq!([vec![1, b'a', 1], vec![0; 16]].concat(), login_start().payload(PROTOCOL_1_20_1)?);
+        assert_eq!([vec![1, b'a'], vec![0; 16]].concat(), login_start().payload(PROTOCOL_1_20_2)?);
+        assert_eq!(VarInt::from(0x12), ServerBoundPacket::KeepAlive { id: 0 }.packet_id(State::Play, PROTOCOL_1_20_1));
+        Ok(())
+    }
+}
@@ -8,13 +8,30 @@ use std::fs::{File, self};
 use std::io::{BufRead, BufReader, Write};
 use std::net::TcpListener;
 use std::path::Path;
-use std::process::{Child, Command, Stdio};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::mpsc::{channel, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
 use tempdir::TempDir;
 use fs_extra::dir::CopyOptions;
 use uuid::Uuid;
 
+use crate::datapack::{self, Datapack};
+use crate::version;
+
 // TODO: Implement app-cds https://nipafx.dev/java-application-class-data-sharing/
 
+/// Datapack of assertion functions installed into every test world, by path within the pack.
+const ASSERTION_PACK: &[(&str, &str)] = &[
+    ("pack.mcmeta", include_str!("../datapacks/assert/pack.mcmeta")),
+    ("data/minecraft/tags/functions/load.json", include_str!("../datapacks/assert/data/minecraft/tags/functions/load.json")),
+    ("data/mctest_assert/functions/load.mcfunction", include_str!("../datapacks/assert/data/mctest_assert/functions/load.mcfunction")),
+    ("data/mctest_assert/functions/block.mcfunction", include_str!("../datapacks/assert/data/mctest_assert/functions/block.mcfunction")),
+    ("data/mctest_assert/functions/entity_count.mcfunction", include_str!("../datapacks/assert/data/mctest_assert/functions/entity_count.mcfunction")),
+    ("data/mctest_assert/functions/score_equals.mcfunction", include_str!("../datapacks/assert/data/mctest_assert/functions/score_equals.mcfunction")),
+    ("data/mctest_assert/functions/storage.mcfunction", include_str!("../datapacks/assert/data/mctest_assert/functions/storage.mcfunction")),
+];
+
 static DIRECTORIES: Lazy<ProjectDirs> = Lazy::new(|| {
     let directories =
         ProjectDirs::from("", "", "mctest").expect("Failed to load application directory.");
@@ -26,45 +43,86 @@ static DIRECTORIES: Lazy<ProjectDirs> = Lazy::new(|| {
 pub struct MinecraftServer {
     dir: TempDir,
     port: u16,
+    protocol: i32,
 }
 
 impl MinecraftServer {
-    pub fn new(version: &str, uuid: Uuid, datapack_path: &Path) -> Result<Self> {
+    /// A server with the datapack at `datapack_path` installed, or the `instrumented` copy of it if given.
+    pub fn new(version: &str, uuid: Uuid, datapack_path: &Path, instrumented: Option<&Datapack>) -> Result<Self> {
+        let protocol = version::protocol(version).ok_or(anyhow!("mctest can't connect to Minecraft {version}"))?;
+        let server_dir = TempDir::new("mctest")?;
+        let port = find_port()?;
+        setup_server_dir(version, &server_dir, port, uuid, datapack_path, instrumented)?;
+        Ok(MinecraftServer {
+            dir: server_dir,
+            port,
+            protocol,
+        })
+    }
+
+    /// A copy of this server listening on another port, to run alongside it.
+    pub fn duplicate(&self) -> Result<Self> {
         let server_dir = TempDir::new("mctest")?;
         let port = find_port()?;
-        setup_server_dir(version, &server_dir, port, uuid, datapack_path)?;
+        let options = CopyOptions { content_only: true, ..CopyOptions::default() };
+        fs_extra::dir::copy(self.dir.path(), server_dir.path(), &options)?;
+        write_server_properties(&server_dir, port)?;
         Ok(MinecraftServer {
             dir: server_dir,
             port,
+            protocol: self.protocol,
         })
     }
 
     pub fn start(self) -> Result<RunningMinecraftServer> {
-        let mut process = Command::new("java")
-            .current_dir(&self.dir.path())
-            .args(["-Xshare:on", "-jar", "server.jar", "--nogui"])
-            .stdout(Stdio::piped())
-            .stdin(Stdio::null())
-            .spawn()?;
-        wait_for_load(&mut process)?;
+        let (process, console, output) = launch(self.dir.path())?;
         Ok(RunningMinecraftServer {
-            _dir: self.dir,
+            dir: self.dir,
             process,
+            console,
+            output,
             port: self.port,
+            protocol: self.protocol,
         })
     }
 }
 
+/// Starts the server in `dir` and waits until it's done loading. Returns the process, its console
+/// and the lines it outputs from then on.
+fn launch(dir: &Path) -> Result<(Child, ChildStdin, Receiver<String>)> {
+    let mut process = Command::new("java")
+        .current_dir(dir)
+        .args(["-Xshare:on", "-jar", "server.jar", "--nogui"])
+        .stdout(Stdio::piped())
+        .stdin(Stdio::piped())
+        .spawn()?;
+    let console = process
+        .stdin
+        .take()
+        .ok_or(anyhow!("Failed to access server stdin"))?;
+    let output = read_output(&mut process)?;
+    wait_for(&output, &DONE)?;
+    Ok((process, console, output))
+}
+
 fn find_port() -> Result<u16> {
     let listener = TcpListener::bind(("localhost", 0))?;
     Ok(listener.local_addr()?.port())
 }
 
-fn setup_server_dir(version: &str, server_dir: &TempDir, port: u16, uuid: Uuid, datapack_path: &Path) -> Result<()> {
+fn setup_server_dir(
+    version: &str,
+    server_dir: &TempDir,
+    port: u16,
+    uuid: Uuid,
+    datapack_path: &Path,
+    instrumented: Option<&Datapack>,
+) -> Result<()> {
     write_eula(server_dir)?;
     write_server_properties(server_dir, port)?;
     write_ops(server_dir, uuid)?;
-    copy_datapack(server_dir, datapack_path)?;
+    copy_datapack(server_dir, datapack_path, instrumented)?;
+    install_assertion_pack(server_dir)?;
     let jar = retrieve_jar(version)?;
     fs::write(server_dir.path().join("server.jar"), jar)?;
     Ok(())
@@ -99,10 +157,14 @@ fn write_ops(server_dir: &TempDir, uuid: Uuid) -> Result<()> {
     Ok(())
 }
 
-fn copy_datapack(server_dir: &TempDir, datapack_path: &Path) -> Result<()> {
+/// Installs the datapack at `datapack_path` into the world, or the `instrumented` copy of it in its place.
+fn copy_datapack(server_dir: &TempDir, datapack_path: &Path, instrumented: Option<&Datapack>) -> Result<()> {
     let path = server_dir.path().join("world/datapacks");
     fs::create_dir_all(&path)?;
-    if datapack_path.is_dir() {
+    if let Some(datapack) = instrumented {
+        // Zipped packs are installed as a directory
+        datapack.write(&path.join(datapack_path.file_stem().unwrap_or(OsStr::new("pack"))))?;
+    } else if datapack_path.is_dir() {
         fs_extra::dir::copy(datapack_path, &path, &CopyOptions::default())?;
     } else {
         fs::copy(datapack_path, path.join(datapack_path.file_name().unwrap_or(OsStr::new("pack.zip"))))?;
@@ -110,6 +172,30 @@ fn copy_datapack(server_dir: &TempDir, datapack_path: &Path) -> Result<()> {
     Ok(())
 }
 
+/// Replaces the datapack of the world with the current contents of `datapack_path`, for `/reload` to pick up.
+fn update_datapack(server_dir: &TempDir, datapack_path: &Path) -> Result<()> {
+    let name = datapack_path.file_name().unwrap_or(OsStr::new("pack.zip"));
+    let installed = server_dir.path().join("world/datapacks").join(name);
+    if installed.is_dir() {
+        fs::remove_dir_all(&installed)?;
+    } else if installed.exists() {
+        fs::remove_file(&installed)?;
+    }
+    copy_datapack(server_dir, datapack_path, None)
+}
+
+fn install_assertion_pack(server_dir: &TempDir) -> Result<()> {
+    let pack_dir = server_dir.path().join("world/datapacks/mctest-assert");
+    for (path, content) in ASSERTION_PACK {
+        for path in datapack::function_paths(path) {
+            let path = pack_dir.join(path);
+            fs::create_dir_all(path.parent().unwrap_or(&pack_dir))?;
+            fs::write(path, content)?;
+        }
+    }
+    Ok(())
+}
+
 fn retrieve_jar(version_id: &str) -> Result<Vec<u8>> {
     if let Some(jar) = read_jar_from_cache(version_id) {
         Ok(jar)
@@ -154,37 +240,23 @@ fn download_jar(version_id: &str) -> Result<Vec<u8>> {
     Ok(reqwest::blocking::get(jar_path)?.bytes()?.into())
 }
 
-fn retrieve_version_manifest() -> Result<Value> {
+pub fn retrieve_version_manifest() -> Result<Value> {
     Ok(
-        reqwest::blocking::get(&format!("{PISTON_META}/mc/game/version_manifest_v2.json"))?
+        reqwest::blocking::get(format!("{PISTON_META}/mc/game/version_manifest_v2.json"))?
             .json()?,
     )
 }
 
-#[cfg(not(debug_assertions))]
-fn wait_for_load(process: &mut Child) -> Result<()> {
-    let stdout = process
-        .stdout
-        .take()
-        .ok_or(anyhow!("Failed to read stdout of minecraft server"))?;
-    let reader = BufReader::new(stdout);
-
-    let regex = Regex::new(".*Done.*")?;
-    for line in reader.lines() {
-        let line = line?;
-
-        if regex.is_match(&line) {
-            break;
-        }
-    }
-
-    Ok(())
-}
-
-#[cfg(debug_assertions)]
-fn wait_for_load(process: &mut Child) -> Result<()> {
-    use std::sync::mpsc::channel;
+static DONE: Lazy<Regex> = Lazy::new(|| Regex::new(".*Done.*").expect("Failed to compile regex"));
+static SAVED: Lazy<Regex> = Lazy::new(|| Regex::new(".*Saved the game.*").expect("Failed to compile regex"));
+/// How long the server may take to save the world and exit once stopped.
+const STOP_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long a killed server may take to exit.
+const KILL_TIMEOUT: Duration = Duration::from_secs(5);
+const EXIT_POLL_INTERVAL: Duration = Duration::from_millis(50);
 
+/// Forwards the lines the server outputs to the returned receiver, echoing them in debug builds.
+fn read_output(process: &mut Child) -> Result<Receiver<String>> {
     let stdout = process
         .stdout
         .take()
@@ -193,30 +265,54 @@ fn wait_for_load(process: &mut Child) -> Result<()> {
 
     let (sender, receiver) = channel();
 
-    std::thread::spawn(move || {
-        let regex = Regex::new(".*Done.*").expect("Failed to compile regex");
+    thread::spawn(move || {
         for line in reader.lines() {
             if let Ok(line) = line {
-                println!("{line}");
-
-                if regex.is_match(&line) {
-                    sender.send(()).expect("Failed to send done signal");
+                if cfg!(debug_assertions) {
+                    eprintln!("{line}");
                 }
+
+                // Nobody may be waiting for output anymore
+                sender.send(line).ok();
             } else {
-                println!("Failed to read");
+                eprintln!("Failed to read");
+                break;
             }
         }
     });
 
-    receiver.recv()?;
+    Ok(receiver)
+}
 
+/// Waits for `process` to exit, failing if it takes longer than `timeout`.
+fn wait_for_exit(process: &mut Child, timeout: Duration) -> Result<()> {
+    let deadline = Instant::now() + timeout;
+    while process.try_wait()?.is_none() {
+        if Instant::now() >= deadline {
+            return Err(anyhow!("Server did not exit within {timeout:?}"));
+        }
+        thread::sleep(EXIT_POLL_INTERVAL);
+    }
     Ok(())
 }
 
+/// Skips output until a line matching `regex`.
+fn wait_for(output: &Receiver<String>, regex: &Regex) -> Result<()> {
+    loop {
+        let line = output.recv()?;
+        if regex.is_match(&line) {
+            return Ok(());
+        }
+    }
+}
+
 pub struct RunningMinecraftServer {
-    _dir: TempDir,
+    dir: TempDir,
     process: Child,
+    console: ChildStdin,
+    output: Receiver<String>,
     port: u16,
+    protocol: i32,
 }
 
 impl RunningMinecraftServer {
@@ -224,21 +320,64 @@ impl RunningMinecraftServer {
         self.port
     }
 
+    /// The version of the network protocol the server speaks.
+    pub fn protocol(&self) -> i32 {
+        self.protocol
+    }
+
+    pub fn update_datapack(&self, datapack_path: &Path) -> Result<()> {
+        update_datapack(&self.dir, datapack_path)
+    }
+
+    /// Saves the world and keeps a copy of it to [`restore`](Self::restore) later.
+    pub fn snapshot(&mut self) -> Result<()> {
+        writeln!(self.console, "save-all flush")?;
+        wait_for(&self.output, &SAVED)?;
+
+        let snapshot = self.dir.path().join("snapshot");
+        if snapshot.exists() {
+            fs::remove_dir_all(&snapshot)?;
+        }
+        fs::create_dir_all(&snapshot)?;
+        let options = CopyOptions { content_only: true, ..CopyOptions::default() };
+        fs_extra::dir::copy(self.dir.path().join("world"), &snapshot, &options)?;
+        Ok(())
+    }
+
+    /// Restarts the server with the world as of the last snapshot. Connected clients are disconnected.
+    pub fn restore(&mut self) -> Result<()> {
+        let snapshot = self.dir.path().join("snapshot");
+        if !snapshot.exists() {
+            return Err(anyhow!("No snapshot of the world to restore"));
+        }
+
+        self.process.kill()?;
+        wait_for_exit(&mut self.process, KILL_TIMEOUT)?;
+
+        let world = self.dir.path().join("world");
+        fs::remove_dir_all(&world)?;
+        fs::create_dir_all(&world)?;
+        let options = CopyOptions { content_only: true, ..CopyOptions::default() };
+        fs_extra::dir::copy(&snapshot, &world, &options)?;
+
+        (self.process, self.console, self.output) = launch(self.dir.path())?;
+        Ok(())
+    }
+
     fn stop(&mut self) {
         // println!("Server stopped");
 
         if self.try_stop_gracefully().is_err() {
             self.process.kill().ok();
+            wait_for_exit(&mut self.process, KILL_TIMEOUT).ok();
         }
     }
 
+    /// Stops the server with its `stop` command, waiting for it to save the world and exit
+    /// before its directory is removed. Fails if it doesn't exit in time.
     fn try_stop_gracefully(&mut self) -> Result<()> {
-        let mut stdin = self
-            .process
-            .stdin
-            .take()
-            .ok_or(anyhow!("Failed to access server stdin"))?;
-        writeln!(stdin, "stop")?;
+        writeln!(self.console, "stop")?;
+        wait_for_exit(&mut self.process, STOP_TIMEOUT)?;
         Ok(())
     }
 }
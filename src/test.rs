@@ -1,96 +1,944 @@
 #![allow(dead_code)]
 
+mod bench;
+mod expect;
+mod filter;
+mod matrix;
+mod parallel;
+mod query;
+mod report;
+mod snapshot;
+mod tap;
+
 use anyhow::{anyhow, Result};
-use regex::Regex;
-use std::io::{BufRead, BufReader, Write};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use std::io::{self, BufRead, BufReader, Write};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 use serde::Deserialize;
+use quartz_nbt::NbtTag;
 
+use crate::datapack::{COVERAGE_HITS, COVERAGE_STORAGE};
 use crate::minecraft_client::{ConnectionReadHalf, ConnectionWriteHalf};
+use snapshot::Comparison;
+use tap::{BailOut, Directive, DirectiveType, Plan, Subtest, TestPoint, Yaml};
+
+pub use bench::{report_benchmarks, run_benchmarks, Baseline, BenchOptions};
+pub use filter::Filter;
+pub use matrix::run_matrix;
+pub use parallel::{run_parallel, Shard};
+pub use report::{Format, Reporter};
+pub use expect::{read_expectations, Expectations};
+pub use snapshot::Snapshots;
+
+/// Prefix of the message the runner sends itself after every command. As the server runs
+/// commands in order, everything received before it is output of that command.
+const SYNC: &str = "mctest:sync ";
+static SYNC_IDS: AtomicU64 = AtomicU64::new(1);
+/// Marks structured results of tests, see [`parse_result`].
+const RESULT: &str = "mctest:result";
+/// Marks the outcomes of functions from the assertion pack, see [`parse_assertion`].
+const ASSERTION: &str = "mctest:assert";
+/// Marks tests that complete in a later tick, see [`parse_async`].
+const ASYNC: &str = "mctest:async";
+/// Marks the completion of asynchronous tests that don't report a structured result.
+const DONE: &str = "mctest:done";
+/// How long to wait between checks for the completion of asynchronous tests.
+const TICK: Duration = Duration::from_millis(50);
 
-pub fn run_tests(reader: ConnectionReadHalf, mut writer: ConnectionWriteHalf) -> Result<()> {
-    writeln!(writer, "/gamerule sendCommandFeedback false")?;
-    
-    writeln!(writer, "/function mctest:plan")?;    
-    let mut reader = BufReader::new(reader);
-    let Plan { length } = reader.read_plan()?;
-    println!("1..{length}");
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// How long to wait for a single test before reporting it as failed.
+    pub timeout: Option<Duration>,
+    /// How long the whole run may take. Tests still to be run once it's exceeded are failed without running.
+    pub global_timeout: Option<Duration>,
+    /// Ids of the test functions to run, instead of the tests listed by `mctest:plan` and `mctest:list`.
+    pub tests: Option<Vec<String>>,
+    /// Which of the tests to run. Selected tests are renumbered as if they were the only ones.
+    pub filter: Filter,
+    pub hooks: Hooks,
+    /// Which of the top level tests to run, if they're shared with other runners.
+    pub shard: Option<Shard>,
+    /// Set when a runner the tests are shared with bails out or fails, so the others start no more tests.
+    pub cancelled: Arc<AtomicBool>,
+    /// How many more times to run a failing test before reporting it as failed.
+    pub retries: usize,
+    /// Seed to shuffle the order of the top level tests with, if they should be.
+    pub shuffle: Option<u64>,
+    /// Whether to reload the datapacks of the server before running the tests.
+    pub reload: bool,
+    /// Where to keep snapshots of the output of tests to compare them with, if they should be.
+    pub snapshots: Option<Snapshots>,
+    /// What the world should hold after each test, by the id of its function.
+    pub expectations: Expectations,
+    /// Number of coverage probes in the functions of the pack, if they're instrumented.
+    pub coverage: Option<usize>,
+}
+
+/// Commands of the optional hook functions of a pack, run around the whole suite and around each test.
+#[derive(Debug, Clone, Default)]
+pub struct Hooks {
+    pub setup: Option<String>,
+    pub teardown: Option<String>,
+    pub before_each: Option<String>,
+    pub after_each: Option<String>,
+}
 
-    let mut test_commands = Vec::new();
-    writeln!(writer, "/function mctest:list")?;
-    for _ in 0..length {
-        let test_command = reader.read_plaintext()?;
-        test_commands.push(test_command);
+impl Hooks {
+    /// The hooks among `mctest:setup`, `mctest:teardown`, `mctest:before_each` and `mctest:after_each`
+    /// for which `exists` holds.
+    pub fn find(exists: impl Fn(&str) -> bool) -> Self {
+        let hook = |id: &str| exists(id).then(|| format!("/function {id}"));
+        Hooks {
+            setup: hook("mctest:setup"),
+            teardown: hook("mctest:teardown"),
+            before_each: hook("mctest:before_each"),
+            after_each: hook("mctest:after_each"),
+        }
     }
+}
 
-    for command in test_commands {
-        writeln!(writer, "{command}")?;
-        let response = reader.read_plaintext()?;
-        println!("{response}");
+/// Counts of the outcomes of the tests in a run, including those within subtests.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Summary {
+    pub passed: usize,
+    pub failed: usize,
+    pub todo: usize,
+    pub skipped: usize,
+    /// Number of plans whose count didn't match the number of tests run.
+    pub plan_mismatches: usize,
+    /// Whether a test aborted the run with `Bail out!`.
+    pub bailed_out: bool,
+    /// Number of passed tests that failed before being retried.
+    pub flaky: usize,
+    /// How often each coverage probe was hit, if the pack is instrumented.
+    pub hits: Vec<u64>,
+}
+
+impl Summary {
+    /// Whether the run went as planned without unexpected failures.
+    pub fn success(&self) -> bool {
+        self.failed == 0 && self.plan_mismatches == 0 && !self.bailed_out
     }
 
-    Ok(())
+    /// Adds the outcomes of another run to those of this one.
+    pub fn add(&mut self, other: &Summary) {
+        self.passed += other.passed;
+        self.failed += other.failed;
+        self.todo += other.todo;
+        self.skipped += other.skipped;
+        self.plan_mismatches += other.plan_mismatches;
+        self.bailed_out |= other.bailed_out;
+        self.flaky += other.flaky;
+        self.add_hits(&other.hits);
+    }
+
+    /// Adds hits of coverage probes to those counted so far.
+    pub fn add_hits(&mut self, hits: &[u64]) {
+        if self.hits.len() < hits.len() {
+            self.hits.resize(hits.len(), 0);
+        }
+        self.hits.iter_mut().zip(hits).for_each(|(total, hits)| *total += hits);
+    }
+
+    fn record(&mut self, test_point: &TestPoint) {
+        if test_point.is_skip() {
+            self.skipped += 1;
+        } else if test_point.is_todo() {
+            self.todo += 1;
+        } else if test_point.ok {
+            self.passed += 1;
+            if is_flaky(test_point) {
+                self.flaky += 1;
+            }
+        } else {
+            self.failed += 1;
+        }
+    }
 }
 
-#[derive(Debug)]
-struct Plan {
-    length: usize,
+/// A connection to a server to run tests over, any number of times.
+pub struct Session {
+    connection: Option<(BufReader<ConnectionReadHalf>, ConnectionWriteHalf)>,
 }
 
-enum DirectiveType {
-    Todo,
-    Skip,
-    Unknown(String)
+impl Session {
+    pub fn new(reader: ConnectionReadHalf, writer: ConnectionWriteHalf) -> Self {
+        Session { connection: Some((BufReader::new(reader), writer)) }
+    }
 }
 
-struct Directive {
-    directive_type: DirectiveType,
-    reason: Option<String>
+pub fn run_tests(
+    session: &mut Session,
+    reporter: &mut dyn Reporter,
+    options: Options,
+    isolation: Option<&mut dyn Isolation<ConnectionReadHalf, ConnectionWriteHalf>>,
+) -> Result<Summary> {
+    let (reader, writer) = session
+        .connection
+        .take()
+        .ok_or(anyhow!("Lost the connection to the server"))?;
+    let mut buffered = isolation.map(Buffered);
+    let mut runner = Runner::new(reader, writer, reporter, options);
+    runner.isolation = buffered.as_mut().map(|isolation| isolation as _);
+    let summary = runner.run_suite();
+    // Isolation may have replaced the connection
+    session.connection = Some((runner.reader, runner.writer));
+    summary
 }
 
-struct TestPoint {
-    ok: bool,
-    // number: Option<usize>,
-    // description: String,
-    // directive: Directive,
-    // yaml: Yaml
+/// Resets the world between tests so they can't affect each other, reconnecting the runner to it.
+pub trait Isolation<R, W> {
+    /// Saves the state of the world to reset to, once the suite is set up.
+    fn snapshot(&mut self) -> Result<()>;
+    /// Resets the world to the snapshot, returning a new connection to it.
+    fn restore(&mut self) -> Result<(R, W)>;
 }
 
-struct Subtest {
+/// Buffers the connections of an isolation for the runner.
+struct Buffered<'a>(&'a mut dyn Isolation<ConnectionReadHalf, ConnectionWriteHalf>);
 
+impl Isolation<BufReader<ConnectionReadHalf>, ConnectionWriteHalf> for Buffered<'_> {
+    fn snapshot(&mut self) -> Result<()> {
+        self.0.snapshot()
+    }
+
+    fn restore(&mut self) -> Result<(BufReader<ConnectionReadHalf>, ConnectionWriteHalf)> {
+        let (reader, writer) = self.0.restore()?;
+        Ok((BufReader::new(reader), writer))
+    }
 }
 
-enum Element {}
+/// A reader whose reads can be bounded by a timeout, after which they fail with [`io::ErrorKind::TimedOut`].
+trait ReadTimeout {
+    fn set_read_timeout(&mut self, timeout: Option<Duration>);
+}
 
-trait BufReadExt {
-    fn read_plaintext(&mut self) -> Result<String>;
-    fn read_plan(&mut self) -> Result<Plan>;
+impl ReadTimeout for BufReader<ConnectionReadHalf> {
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.get_mut().set_read_timeout(timeout);
+    }
 }
 
-impl<R: BufRead> BufReadExt for R {
-    fn read_plaintext(&mut self) -> Result<String> {
-        let mut buf = String::new();
-        self.read_line(&mut buf)?;
-        let text_component: TextComponent = serde_json::from_str(&mut buf)?;
-        Ok(component_to_plaintext(text_component))
+struct Runner<'a, R, W> {
+    reader: R,
+    writer: W,
+    reporter: &'a mut dyn Reporter,
+    options: Options,
+    /// When the global timeout is exceeded.
+    deadline: Option<Instant>,
+    /// Game time of the last sync.
+    gametime: i64,
+    summary: Summary,
+    isolation: Option<&'a mut dyn Isolation<R, W>>,
+    /// Whether a test ran since the world was last restored.
+    dirty: bool,
+}
+
+/// A chat message, both as received and as plaintext.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub json: String,
+    pub text: String,
+}
+
+impl Message {
+    /// The component of this message if it is structured, ie. its root component has the insertion `marker`.
+    fn structured(&self, marker: &str) -> Option<TextComponent> {
+        let component: TextComponent = serde_json::from_str(&self.json).ok()?;
+        (component.insertion() == Some(marker)).then_some(component)
+    }
+}
+
+/// A plan and the commands of the tests it plans.
+struct Listing {
+    subtest: Option<Subtest>,
+    plan: Plan,
+    commands: Vec<String>,
+}
+
+/// Everything the server responded to a command with.
+#[derive(Debug, Clone)]
+pub struct Execution {
+    pub command: String,
+    pub messages: Vec<Message>,
+    pub duration: Duration,
+    pub ticks: i64,
+    /// The timeout the command exceeded, if it did.
+    pub timed_out: Option<Duration>,
+    /// The tick budget an asynchronous test exceeded, if it did.
+    pub ticks_exceeded: Option<i64>,
+    /// The output of the command and the selected world state after it, if snapshots are taken.
+    pub snapshot: Option<String>,
+    /// How the world differs from what's expected after the test, if it does.
+    pub unmet_expectations: Vec<String>,
+}
+
+impl<'a, R: BufRead + ReadTimeout, W: Write> Runner<'a, R, W> {
+    fn new(reader: R, writer: W, reporter: &'a mut dyn Reporter, options: Options) -> Self {
+        Runner {
+            reader,
+            writer,
+            reporter,
+            options,
+            deadline: None,
+            gametime: 0,
+            summary: Summary::default(),
+            isolation: None,
+            dirty: false,
+        }
+    }
+
+    /// Runs the tests given in the options, or else those listed by `mctest:plan` and `mctest:list`,
+    /// between the `setup` and `teardown` hooks.
+    fn run_suite(&mut self) -> Result<Summary> {
+        self.deadline = self.options.global_timeout.map(|timeout| Instant::now() + timeout);
+        self.reporter.start()?;
+        if let Some(seed) = self.options.shuffle.filter(|_| self.options.shard.is_none_or(|shard| shard.is_first())) {
+            self.reporter.shuffled(seed, 0)?;
+        }
+        self.query("/gamerule sendCommandFeedback false")?;
+        if self.options.reload {
+            self.reload()?;
+        }
+
+        if !self.hook(|hooks| &hooks.setup)? {
+            if self.isolation.is_some() {
+                self.collect_coverage()?;
+            }
+            if let Some(isolation) = &mut self.isolation {
+                isolation.snapshot()?;
+            }
+            self.run_listed()?;
+            if !self.summary.bailed_out {
+                self.hook(|hooks| &hooks.teardown)?;
+            }
+        }
+        self.collect_coverage()?;
+
+        self.reporter.finish(&self.summary)?;
+        Ok(self.summary.clone())
     }
 
-    fn read_plan(&mut self) -> Result<Plan> {
-        let text = self.read_plaintext()?;
+    fn run_listed(&mut self) -> Result<()> {
+        if let Some(tests) = &self.options.tests {
+            let plan = Plan { count: tests.len(), reason: None };
+            let commands = tests.iter().map(|id| format!("/function {id}")).collect();
+            let (plan, commands) = self.select(plan, commands)?;
+            self.run_plan(plan, commands, 0)?;
+        } else {
+            let mut listing = self.query("/function mctest:plan")?;
+            listing.extend(self.query("/function mctest:list")?);
+            if !self.bail_out(&listing)? {
+                let Listing { plan, commands, .. } = parse_listing(&listing)
+                    .ok_or(anyhow!("Failed to read test plan from mctest:plan"))??;
+                let (plan, commands) = self.select(plan, commands)?;
+                self.run_plan(plan, commands, 0)?;
+            }
+        }
+        Ok(())
+    }
 
-        let length = Regex::new(r"1..(?<length>\d+)")?
-            .captures(&text)
-            .ok_or(anyhow!("Failed to parse `{text}` as a Plan"))?
-            .name("length")
-            .ok_or(anyhow!("Malformed regex"))?
-            .as_str()
-            .parse()?;
+    /// Narrows a plan down to the tests matching the filter, in shuffled order if they should be.
+    /// The plan is checked against the listed tests first, as the narrowed plan always matches them.
+    fn select(&mut self, plan: Plan, commands: Vec<String>) -> Result<(Plan, Vec<String>)> {
+        let (plan, mut commands) = if self.options.filter.is_empty() {
+            (plan, commands)
+        } else {
+            let reports_plan = self.options.shard.is_none_or(|shard| shard.is_first());
+            if commands.len() != plan.count && reports_plan {
+                self.summary.plan_mismatches += 1;
+                self.reporter.plan_mismatch(&plan, commands.len(), 0)?;
+            }
+            let commands: Vec<String> = commands
+                .into_iter()
+                .filter(|command| self.options.filter.matches(command))
+                .collect();
+            (Plan { count: commands.len(), reason: None }, commands)
+        };
 
-        Ok(Plan { length })
+        if let Some(seed) = self.options.shuffle {
+            commands.shuffle(&mut StdRng::seed_from_u64(seed));
+        }
+        Ok((plan, commands))
+    }
+
+    /// Runs a hook if the pack has it, returning whether it aborted the run with `Bail out!`.
+    /// Timed out hooks are otherwise ignored, their tests will likely time out too.
+    fn hook(&mut self, hook: fn(&Hooks) -> &Option<String>) -> Result<bool> {
+        let Some(command) = hook(&self.options.hooks).clone() else {
+            return Ok(false);
+        };
+        let execution = self.run(&command)?;
+        self.bail_out(&execution.messages)
+    }
+
+    /// Resets the world if a test changed it since it was last reset, with isolation enabled.
+    fn isolate(&mut self) -> Result<()> {
+        // Once the global timeout has passed, remaining tests time out without running anyway
+        let out_of_time = self.deadline.is_some_and(|deadline| Instant::now() >= deadline);
+        if self.isolation.is_none() || !self.dirty || out_of_time {
+            return Ok(());
+        }
+        self.collect_coverage()?;
+        if let Some(isolation) = &mut self.isolation {
+            (self.reader, self.writer) = isolation.restore()?;
+        }
+        self.dirty = false;
+        // Also catches up with the game time of the new server. The global timeout may have passed while
+        // it restarted, this is waited for regardless so the connection is in a known state.
+        let deadline = self.deadline.take();
+        let set_up = self.query("/gamerule sendCommandFeedback false");
+        self.deadline = deadline;
+        set_up?;
+        Ok(())
+    }
+
+    /// Adds the hits of the coverage probes to the summary and resets them. They're counted in the world,
+    /// so with isolation they're also collected before it's saved or restored. Hits are collected even
+    /// once the global timeout has passed, so they can still be reported.
+    fn collect_coverage(&mut self) -> Result<()> {
+        let Some(probes) = self.options.coverage else {
+            return Ok(());
+        };
+
+        let deadline = self.deadline.take();
+        let hits = self.read_hits(probes);
+        self.deadline = deadline;
+        self.summary.add_hits(&hits?);
+        Ok(())
+    }
+
+    /// Reads the hits of the `probes` coverage probes and resets them. The instrumented pack copies
+    /// them all to storage, so they take the same two queries however many probes there are.
+    fn read_hits(&mut self, probes: usize) -> Result<Vec<u64>> {
+        self.query(&format!("/function {COVERAGE_HITS}"))?;
+        let Some(NbtTag::List(hits)) = self.storage(COVERAGE_STORAGE, "hits")? else {
+            return Err(anyhow!("Failed to read coverage hits from storage {COVERAGE_STORAGE}"));
+        };
+        if hits.len() != probes {
+            return Err(anyhow!("Expected hits of {probes} coverage probes but found {}", hits.len()));
+        }
+        hits.iter()
+            .map(|hits| match hits {
+                // Scores wrap around past the largest int
+                NbtTag::Int(hits) => Ok(u64::from(*hits as u32)),
+                _ => Err(anyhow!("Coverage hits should be ints, not {hits}")),
+            })
+            .collect()
+    }
+
+    /// Reloads the datapacks of the server, waiting until the reload is done. It completes in a later tick,
+    /// when the load function of the assertion pack marks it done in storage.
+    fn reload(&mut self) -> Result<()> {
+        self.query("/data remove storage mctest:runner reloaded")?;
+        let start = Instant::now();
+        self.query("/reload")?;
+        while self.storage("mctest:runner", "reloaded")?.is_none() {
+            if self.options.timeout.is_some_and(|timeout| start.elapsed() > timeout) {
+                return Err(anyhow!("Reloading datapacks timed out"));
+            }
+            thread::sleep(TICK);
+        }
+        Ok(())
+    }
+
+    /// Runs a command the rest of the run depends on, failing if it times out.
+    fn query(&mut self, command: &str) -> Result<Vec<Message>> {
+        let execution = self.run(command)?;
+        match execution.timed_out {
+            Some(timeout) => Err(anyhow!("`{command}` timed out after {timeout:?}")),
+            None => Ok(execution.messages),
+        }
+    }
+
+    /// Runs `command` and collects its output, giving up once the test or global timeout is exceeded.
+    /// Output of asynchronous commands is collected until they signal completion, see [`parse_async`].
+    fn run(&mut self, command: &str) -> Result<Execution> {
+        let start = Instant::now();
+        let remaining = self.deadline.map(|deadline| deadline.saturating_duration_since(start));
+        let timeout = match (self.options.timeout, remaining) {
+            (Some(timeout), Some(remaining)) => Some(timeout.min(remaining)),
+            (timeout, remaining) => timeout.or(remaining),
+        };
+
+        let start_gametime = self.gametime;
+        let deadline = timeout.map(|timeout| start + timeout);
+        let (mut messages, mut timed_out) = if timeout == Some(Duration::ZERO) {
+            (Vec::new(), true)
+        } else {
+            writeln!(self.writer, "{command}")?;
+            self.sync(deadline)?
+        };
+
+        let mut ticks_exceeded = None;
+        if let Some(Async { ticks }) = parse_async(&messages).filter(|_| !timed_out) {
+            while !is_complete(command, &messages) {
+                if let Some(ticks) = ticks.filter(|ticks| self.gametime - start_gametime >= *ticks) {
+                    ticks_exceeded = Some(ticks);
+                    break;
+                }
+                thread::sleep(TICK);
+                let (more, timed_out_now) = self.sync(deadline)?;
+                messages.extend(more);
+                if timed_out_now {
+                    timed_out = true;
+                    break;
+                }
+            }
+        }
+
+        Ok(Execution {
+            command: command.to_owned(),
+            messages,
+            duration: start.elapsed(),
+            ticks: if timed_out { 0 } else { self.gametime - start_gametime },
+            timed_out: timeout.filter(|_| timed_out),
+            ticks_exceeded,
+            snapshot: None,
+            unmet_expectations: Vec::new(),
+        })
+    }
+
+    /// Waits until the server has run every command sent so far, returning the messages received meanwhile
+    /// and whether `deadline` passed first.
+    fn sync(&mut self, deadline: Option<Instant>) -> Result<(Vec<Message>, bool)> {
+        // Unique among all runs, so syncs of timed out commands arriving late can be told apart
+        let sync_id = SYNC_IDS.fetch_add(1, Ordering::Relaxed);
+        writeln!(self.writer, "/execute store result storage mctest:runner gametime int 1 run time query gametime")?;
+        writeln!(self.writer, r#"/tellraw @s ["{SYNC}{sync_id} ",{{"storage":"mctest:runner","nbt":"gametime"}}]"#)?;
+
+        let mut messages = Vec::new();
+        loop {
+            let timeout = deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+            if timeout == Some(Duration::ZERO) {
+                return Ok((messages, true));
+            }
+            self.reader.set_read_timeout(timeout);
+
+            let message = match self.reader.read_message() {
+                Ok(message) => message,
+                Err(e) if is_timeout(&e) => return Ok((messages, true)),
+                Err(e) => return Err(e),
+            };
+
+            if let Some(sync) = message.text.strip_prefix(SYNC) {
+                let (id, gametime) = sync
+                    .split_once(' ')
+                    .ok_or(anyhow!("Malformed sync message `{sync}`"))?;
+                if id.parse::<u64>()? == sync_id {
+                    self.gametime = gametime.parse()?;
+                    return Ok((messages, false));
+                }
+                // Everything up to the late sync of an earlier command was output of that command
+                messages.clear();
+                continue;
+            }
+
+            self.reporter.chat_received(&message)?;
+            messages.push(message);
+        }
+    }
+
+    /// Reports the first `Bail out!` among `messages`, if there is one. Returns whether there was.
+    fn bail_out(&mut self, messages: &[Message]) -> Result<bool> {
+        let bail_out = messages.iter().find_map(|message| message.text.parse::<BailOut>().ok());
+        if let Some(bail_out) = bail_out {
+            self.summary.bailed_out = true;
+            self.reporter.bail_out(&bail_out)?;
+        }
+        Ok(self.summary.bailed_out)
+    }
+
+    /// Runs the test commands of a plan in order. Returns whether every test passed and
+    /// as many tests were run as planned.
+    /// When sharded, only the top level tests of the shard are run and only the first shard reports the top level plan.
+    fn run_plan(&mut self, plan: Plan, commands: Vec<String>, depth: usize) -> Result<bool> {
+        let shard = self.options.shard.filter(|_| depth == 0);
+        let reports_plan = shard.is_none_or(|shard| shard.is_first());
+        if reports_plan {
+            self.reporter.plan(&plan, depth)?;
+        }
+
+        let mut passed = commands.len() == plan.count;
+        if !passed && reports_plan {
+            self.summary.plan_mismatches += 1;
+            self.reporter.plan_mismatch(&plan, commands.len(), depth)?;
+        }
+        for (i, command) in commands.into_iter().enumerate() {
+            if shard.is_some_and(|shard| !shard.includes(i + 1)) {
+                continue;
+            }
+            if self.options.cancelled.load(Ordering::Relaxed) {
+                return Ok(false);
+            }
+            let Some(test_point) = self.run_test(i + 1, &command, depth)? else {
+                return Ok(false);
+            };
+            passed &= test_point.ok || test_point.is_todo();
+        }
+
+        Ok(passed)
+    }
+
+    /// Runs a single test command. A test responding with a plan (optionally preceded by a
+    /// `# Subtest: name` comment) and a list of commands is run as a subtest of those commands.
+    /// The `before_each` and `after_each` hooks are run around the command, including those of subtests.
+    /// With isolation, the command runs in the world as it was before any test. Failing tests are
+    /// run again as many times as there are retries, and marked as flaky if they pass on a retry.
+    /// Returns `None` if the run was aborted by a `Bail out!`.
+    fn run_test(&mut self, number: usize, command: &str, depth: usize) -> Result<Option<TestPoint>> {
+        let Some(execution) = self.attempt(number, command, depth, 1)? else {
+            return Ok(None);
+        };
+
+        if let Some(listing) = parse_listing(&execution.messages).filter(|_| execution.timed_out.is_none()) {
+            let Listing { subtest, plan, commands } = listing?;
+            let name = subtest
+                .and_then(|subtest| subtest.name)
+                .unwrap_or_else(|| command.to_owned());
+            self.reporter.subtest(&Subtest { name: Some(name.clone()) }, depth)?;
+            let passed = self.run_plan(plan, commands, depth + 1)?;
+            if self.summary.bailed_out {
+                return Ok(None);
+            }
+            let test_point = TestPoint {
+                number: Some(number),
+                description: Some(name),
+                ..TestPoint::new(passed)
+            };
+            self.reporter.test_point(&test_point, None, depth)?;
+            Ok(Some(test_point))
+        } else {
+            let mut execution = execution;
+            let mut test_point = self.judge(number, &execution)?;
+            let mut attempts = 1;
+            while !test_point.ok && !test_point.is_todo() && !test_point.is_skip() && attempts <= self.options.retries {
+                attempts += 1;
+                let Some(retry) = self.attempt(number, command, depth, attempts)? else {
+                    return Ok(None);
+                };
+                execution = retry;
+                test_point = self.judge(number, &execution)?;
+            }
+            if attempts > 1 {
+                let yaml = test_point.yaml.get_or_insert_with(Yaml::new);
+                yaml.insert("attempts".into(), attempts.into());
+                if test_point.ok {
+                    yaml.insert("flaky".into(), true.into());
+                }
+            }
+
+            self.summary.record(&test_point);
+            self.reporter.test_point(&test_point, Some(&execution), depth)?;
+            Ok(Some(test_point))
+        }
+    }
+
+    /// Makes an attempt at running a test command. Returns `None` if the run was aborted by a `Bail out!`.
+    fn attempt(&mut self, number: usize, command: &str, depth: usize, attempt: usize) -> Result<Option<Execution>> {
+        self.isolate()?;
+        if self.hook(|hooks| &hooks.before_each)? {
+            return Ok(None);
+        }
+        if attempt == 1 {
+            self.reporter.test_start(number, command, depth)?;
+        }
+        let mut execution = self.run(command)?;
+        self.dirty = true;
+        // The world is left alone after a timeout, the test fails anyway and queries would time out too
+        if parse_listing(&execution.messages).is_none() && execution.timed_out.is_none() {
+            execution.snapshot = self.take_snapshot(&execution)?;
+            execution.unmet_expectations = self.check_expectations(&execution)?;
+        }
+        if self.bail_out(&execution.messages)? || self.hook(|hooks| &hooks.after_each)? {
+            return Ok(None);
+        }
+        Ok(Some(execution))
+    }
+
+    /// Interprets an execution as the test point numbered `number`, failing passing tests that left
+    /// the world other than expected or whose snapshot doesn't match the one kept.
+    fn judge(&self, number: usize, execution: &Execution) -> Result<TestPoint> {
+        let mut test_point = to_test_point(number, execution);
+        if !test_point.ok {
+            return Ok(test_point);
+        }
+        if !execution.unmet_expectations.is_empty() {
+            test_point.ok = false;
+            let yaml = test_point.yaml.get_or_insert_with(Yaml::new);
+            yaml.insert("message".into(), "World state does not match expectations".into());
+            yaml.insert("expectations".into(), execution.unmet_expectations.clone().into());
+            return Ok(test_point);
+        }
+        let (Some(snapshots), Some(snapshot)) = (&self.options.snapshots, &execution.snapshot) else {
+            return Ok(test_point);
+        };
+
+        let (message, diff) = match snapshots.compare(&execution.command, snapshot)? {
+            Comparison::Matched | Comparison::Written => return Ok(test_point),
+            // A missing snapshot is no proof the output is right, eg. after `__snapshots__` was deleted
+            Comparison::Created => ("No snapshot to compare with, wrote one", None),
+            Comparison::Mismatched(diff) => ("Snapshot does not match", Some(diff)),
+        };
+        test_point.ok = false;
+        let yaml = test_point.yaml.get_or_insert_with(Yaml::new);
+        yaml.insert("message".into(), message.into());
+        yaml.insert("snapshot".into(), snapshots.path(&execution.command).display().to_string().into());
+        if let Some(diff) = diff {
+            yaml.insert("diff".into(), diff.into());
+        }
+        Ok(test_point)
     }
 }
 
-fn json_to_plaintext(json: &str) -> Result<String> {
-    Ok(component_to_plaintext(serde_json::from_str(json)?))
+/// Whether a test passed only after being retried.
+fn is_flaky(test_point: &TestPoint) -> bool {
+    test_point
+        .yaml
+        .as_ref()
+        .and_then(|yaml| yaml.get("flaky"))
+        .and_then(|flaky| flaky.as_bool())
+        .unwrap_or(false)
+}
+
+/// Interprets messages as an optional `# Subtest` comment, a plan and then the test commands,
+/// which should be as many as planned. Returns `None` if the messages don't start with a plan.
+fn parse_listing(messages: &[Message]) -> Option<Result<Listing>> {
+    let mut messages = messages.iter().map(|message| message.text.as_str()).peekable();
+    let subtest = messages.peek()?.parse::<Subtest>().ok();
+    if subtest.is_some() {
+        messages.next();
+    }
+
+    let plan = match messages.next()?.parse::<Plan>() {
+        Ok(plan) => plan,
+        Err(e) if subtest.is_some() => return Some(Err(e)),
+        Err(_) => return None,
+    };
+
+    let commands = messages.map(str::to_owned).collect();
+    Some(Ok(Listing { subtest, plan, commands }))
+}
+
+/// Interprets the response of an execution as the test point numbered `number`. The response
+/// is the first structured result, or the first message if there is none.
+/// Tests reporting only assertions pass if every assertion did, and fail any other test whose assertions fail.
+/// Responses that aren't valid test points are reported as failures. Failures are
+/// annotated with a YAML diagnostic block describing the execution.
+fn to_test_point(number: usize, execution: &Execution) -> TestPoint {
+    let mut assertions = Vec::new();
+    let mut messages = Vec::new();
+    for message in &execution.messages {
+        let foreign = message
+            .structured(RESULT)
+            .is_some_and(|component| !belongs_to(&component, &execution.command));
+        if foreign || message.structured(ASYNC).is_some() || message.structured(DONE).is_some() {
+            continue;
+        }
+        match message.structured(ASSERTION).map(|component| parse_assertion(&component)) {
+            Some(Ok(assertion)) => assertions.push(assertion),
+            _ => messages.push(message),
+        }
+    }
+
+    let index = messages
+        .iter()
+        .position(|message| message.structured(RESULT).is_some())
+        .unwrap_or(0);
+    let response = messages.get(index).copied();
+    let result = response.map(|response| match response.structured(RESULT) {
+        Some(component) => parse_result(&component),
+        None => response.text.parse::<TestPoint>(),
+    });
+    let ticks_exceeded = execution.ticks_exceeded.map(|ticks| format!("Did not complete within {ticks} ticks"));
+    let (mut test_point, mut message) = match (execution.timed_out, result) {
+        (Some(timeout), _) => (TestPoint::new(false), Some(format!("Timed out after {timeout:?}"))),
+        (None, _) if execution.ticks_exceeded.is_some() => (TestPoint::new(false), ticks_exceeded),
+        (None, Some(Ok(test_point))) => match test_point.number {
+            Some(n) if n != number => (
+                TestPoint { ok: false, ..test_point },
+                Some(format!("Expected test number {number} but found {n}")),
+            ),
+            _ => (test_point, None),
+        },
+        (None, Some(Err(_))) => (TestPoint::new(false), Some("Invalid test output".to_owned())),
+        (None, None) if !assertions.is_empty() => (TestPoint::new(true), None),
+        (None, None) => (TestPoint::new(false), Some("No test output".to_owned())),
+    };
+
+    let failed_assertions: Vec<String> = assertions
+        .into_iter()
+        .filter(|assertion| !assertion.passed)
+        .map(|assertion| assertion.message)
+        .collect();
+    if !failed_assertions.is_empty() {
+        test_point.ok = false;
+        message.get_or_insert_with(|| "Assertion failed".to_owned());
+    }
+
+    test_point.number = Some(number);
+    test_point.description.get_or_insert_with(|| execution.command.clone());
+
+    if !test_point.ok {
+        let mut yaml = test_point.yaml.take().unwrap_or_default();
+        if let Some(message) = message {
+            yaml.insert("message".into(), message.into());
+        }
+        if !failed_assertions.is_empty() {
+            yaml.insert("assertions".into(), failed_assertions.into());
+        }
+        yaml.insert("command".into(), execution.command.clone().into());
+        if let Some(response) = response {
+            yaml.insert("response".into(), response.json.clone().into());
+        }
+        yaml.insert("duration_ms".into(), (execution.duration.as_millis() as u64).into());
+        yaml.insert("ticks".into(), execution.ticks.into());
+        let extra: Vec<String> = messages
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| *i != index)
+            .map(|(_, message)| message.text.clone())
+            .collect();
+        if !extra.is_empty() {
+            yaml.insert("extra".into(), extra.into());
+        }
+        test_point.yaml = Some(yaml);
+    }
+
+    test_point
+}
+
+/// Reads a test point from a structured result, a component marked with the insertion `mctest:result`
+/// whose text is `ok` or `not ok` and whose children hold the fields named by their insertions, eg.
+/// `{"text":"not ok","insertion":"mctest:result","extra":[{"text":"jumps","insertion":"description"},{"text":"landed early","insertion":"diagnostic"}]}`.
+/// The fields are `description`, `todo` and `skip` with the reason, and any number of `diagnostic`s.
+/// Being marked by insertions rather than text, results survive styling and translation and can't be confused with other chat.
+fn parse_result(component: &TextComponent) -> Result<TestPoint> {
+    let ok = match component.text().trim() {
+        "ok" => true,
+        "not ok" => false,
+        status => return Err(anyhow!("Unknown result status `{status}`")),
+    };
+
+    let mut test_point = TestPoint::new(ok);
+    let mut diagnostics = Vec::new();
+    for field in component.extra() {
+        let Some(name) = field.insertion().map(str::to_owned) else {
+            continue;
+        };
+        let value = component_to_plaintext(field);
+        let directive = |directive_type| Directive {
+            directive_type,
+            reason: Some(value.clone()).filter(|reason| !reason.is_empty()),
+        };
+        match name.as_str() {
+            "description" => test_point.description = Some(value),
+            "todo" => test_point.directive = Some(directive(DirectiveType::Todo)),
+            "skip" => test_point.directive = Some(directive(DirectiveType::Skip)),
+            "diagnostic" => diagnostics.push(value),
+            _ => {}
+        }
+    }
+    if !diagnostics.is_empty() {
+        let mut yaml = Yaml::new();
+        yaml.insert("diagnostics".into(), diagnostics.into());
+        test_point.yaml = Some(yaml);
+    }
+
+    Ok(test_point)
+}
+
+/// The declaration of an asynchronous test, a message marked with the insertion `mctest:async` whose text
+/// is the number of ticks the test may take, if it's limited, eg. `{"text":"100","insertion":"mctest:async"}`.
+/// Asynchronous tests complete once they send a structured result or a message marked `mctest:done`.
+/// Both may name the test they complete in a child marked `test`, eg.
+/// `{"text":"ok","insertion":"mctest:result","extra":[{"text":"example:test/walk","insertion":"test"}]}`,
+/// so that results of tests that already timed out can't be mistaken for those of later tests.
+struct Async {
+    ticks: Option<i64>,
+}
+
+fn parse_async(messages: &[Message]) -> Option<Async> {
+    let component = messages.iter().find_map(|message| message.structured(ASYNC))?;
+    Some(Async { ticks: component_to_plaintext(component).trim().parse().ok() })
+}
+
+/// Whether an asynchronous test run by `command` signalled completion among `messages`.
+fn is_complete(command: &str, messages: &[Message]) -> bool {
+    messages.iter().any(|message| {
+        message
+            .structured(RESULT)
+            .or_else(|| message.structured(DONE))
+            .is_some_and(|component| belongs_to(&component, command))
+    })
+}
+
+/// Whether a structured message belongs to the test run by `command`, ie. it doesn't name another test.
+fn belongs_to(component: &TextComponent, command: &str) -> bool {
+    component
+        .extra()
+        .into_iter()
+        .find(|field| field.insertion() == Some("test"))
+        .is_none_or(|field| component_to_plaintext(field).trim() == function_id(command))
+}
+
+/// The id of the function run by `command`, or the command itself if it doesn't run a function.
+fn function_id(command: &str) -> &str {
+    command
+        .strip_prefix("/function ")
+        .and_then(|rest| rest.split_whitespace().next())
+        .unwrap_or(command)
+}
+
+/// The outcome of a function of the assertion pack.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Assertion {
+    passed: bool,
+    message: String,
+}
+
+/// Reads an assertion from a component marked with the insertion `mctest:assert`, whose text is
+/// `pass` or `fail` and whose children make up the message, eg.
+/// `{"text":"fail","insertion":"mctest:assert","extra":[" expected 1 entities matching @e but found 0"]}`.
+fn parse_assertion(component: &TextComponent) -> Result<Assertion> {
+    let passed = match component.text().trim() {
+        "pass" => true,
+        "fail" => false,
+        outcome => return Err(anyhow!("Unknown assertion outcome `{outcome}`")),
+    };
+    let message: String = component.extra().into_iter().map(component_to_plaintext).collect();
+
+    Ok(Assertion { passed, message: message.trim().to_owned() })
+}
+
+fn is_timeout(error: &anyhow::Error) -> bool {
+    matches!(
+        error.downcast_ref::<io::Error>().map(io::Error::kind),
+        Some(io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock)
+    )
+}
+
+trait BufReadExt {
+    fn read_message(&mut self) -> Result<Message>;
+}
+
+impl<R: BufRead> BufReadExt for R {
+    fn read_message(&mut self) -> Result<Message> {
+        let mut json = String::new();
+        self.read_line(&mut json)?;
+        let text_component: TextComponent = serde_json::from_str(&json)?;
+        json.truncate(json.trim_end().len());
+
+        Ok(Message {
+            json,
+            text: component_to_plaintext(text_component),
+        })
+    }
 }
 
 #[derive(Deserialize, Clone)]
@@ -100,12 +948,15 @@ enum TextComponent {
         text: String,
         #[serde(default = "Vec::new")]
         extra: Vec<TextComponent>,
+        insertion: Option<String>,
     },
     Translate {
         translate: String,
         #[serde(default = "Vec::new")]
         extra: Vec<TextComponent>,
-    }
+        insertion: Option<String>,
+    },
+    Plain(String),
 }
 
 impl TextComponent {
@@ -113,6 +964,7 @@ impl TextComponent {
         match self {
             TextComponent::Text { text, .. } => text.clone(),
             TextComponent::Translate { translate, .. } => translate.clone(),
+            TextComponent::Plain(text) => text.clone(),
         }
     }
 
@@ -120,13 +972,15 @@ impl TextComponent {
         match self {
             TextComponent::Text { extra, .. } => extra.clone(),
             TextComponent::Translate { extra, .. } => extra.clone(),
+            TextComponent::Plain(_) => Vec::new(),
         }
     }
 
-    fn is_text(&self) -> bool {
+    /// The text inserted into the chat input when shift-clicking the component, which mctest uses as a marker.
+    fn insertion(&self) -> Option<&str> {
         match self {
-            TextComponent::Text { .. } => true,
-            _ => false,
+            TextComponent::Text { insertion, .. } | TextComponent::Translate { insertion, .. } => insertion.as_deref(),
+            TextComponent::Plain(_) => None,
         }
     }
 }
@@ -138,4 +992,908 @@ fn component_to_plaintext(text_component: TextComponent) -> String {
         text.push_str(&component_to_plaintext(tc));
     }
     text
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use report::TapReporter;
+    use crate::minecraft_client::MAX_COMMAND_LENGTH;
+    use std::cell::RefCell;
+    use std::collections::{HashMap, VecDeque};
+    use std::io::{self, Read};
+    use std::rc::Rc;
+
+    /// Stands in for a server connection, answering each command with canned `tellraw` output.
+    #[derive(Clone, Default)]
+    struct FakeServer {
+        functions: Rc<HashMap<String, Vec<String>>>,
+        /// Commands after which the server stalls until it receives another command.
+        stalls: Rc<Vec<String>>,
+        /// Output held back while stalled.
+        stalled: Rc<RefCell<Option<Vec<String>>>>,
+        input: Rc<RefCell<String>>,
+        output: Rc<RefCell<VecDeque<u8>>>,
+        gametime: Rc<RefCell<i64>>,
+        /// Output of the first runs of commands, one run after another, before that in `functions`.
+        runs: Rc<RefCell<HashMap<String, VecDeque<Vec<String>>>>>,
+        /// Output of commands delayed by a number of ticks, like that of scheduled functions.
+        schedules: Rc<HashMap<String, (i64, Vec<String>)>>,
+        /// Lines of scheduled output with the game time to send them at.
+        scheduled: Rc<RefCell<Vec<(i64, String)>>>,
+        /// Commands run other than those of the runner itself, in order.
+        commands: Rc<RefCell<Vec<String>>>,
+        /// Syncs until a `/reload` completes, if one is in progress.
+        reloading: Rc<RefCell<Option<usize>>>,
+        /// What `score` and `nbt` components resolve to, by their JSON.
+        world: Rc<HashMap<String, String>>,
+    }
+
+    impl FakeServer {
+        fn new(functions: &[(&str, &[&str])]) -> Self {
+            let functions = functions
+                .iter()
+                .map(|(command, lines)| (command.to_string(), lines.iter().map(|l| l.to_string()).collect()))
+                .collect();
+            FakeServer { functions: Rc::new(functions), ..Default::default() }
+        }
+
+        fn stalling(self, commands: &[&str]) -> Self {
+            let stalls = commands.iter().map(|command| command.to_string()).collect();
+            FakeServer { stalls: Rc::new(stalls), ..self }
+        }
+
+        fn answering_runs(self, runs: &[(&str, &[&[&str]])]) -> Self {
+            let runs = runs
+                .iter()
+                .map(|(command, runs)| {
+                    let runs = runs.iter().map(|lines| lines.iter().map(|l| l.to_string()).collect()).collect();
+                    (command.to_string(), runs)
+                })
+                .collect();
+            FakeServer { runs: Rc::new(RefCell::new(runs)), ..self }
+        }
+
+        fn scheduling(self, schedules: &[(&str, i64, &[&str])]) -> Self {
+            let schedules = schedules
+                .iter()
+                .map(|(command, ticks, lines)| (command.to_string(), (*ticks, lines.iter().map(|l| l.to_string()).collect())))
+                .collect();
+            FakeServer { schedules: Rc::new(schedules), ..self }
+        }
+
+        fn with_world(self, world: &[(serde_json::Value, &str)]) -> Self {
+            let world = world.iter().map(|(component, value)| (component.to_string(), value.to_string())).collect();
+            FakeServer { world: Rc::new(world), ..self }
+        }
+
+        fn runner<'a>(&self, reporter: &'a mut dyn Reporter) -> Runner<'a, BufReader<FakeServer>, FakeServer> {
+            self.runner_with(reporter, Options::default())
+        }
+
+        fn runner_with<'a>(&self, reporter: &'a mut dyn Reporter, options: Options) -> Runner<'a, BufReader<FakeServer>, FakeServer> {
+            Runner::new(BufReader::new(self.clone()), self.clone(), reporter, options)
+        }
+
+        /// Runs the suite, returning the TAP it reports.
+        fn tap(&self) -> Result<String> {
+            self.tap_with(Options::default())
+        }
+
+        fn tap_with(&self, options: Options) -> Result<String> {
+            let mut reporter = TapReporter::new(Vec::new());
+            self.runner_with(&mut reporter, options).run_suite()?;
+            Ok(String::from_utf8(reporter.into_inner())?)
+        }
+
+        fn summary(&self) -> Result<Summary> {
+            self.runner(&mut TapReporter::new(io::sink())).run_suite()
+        }
+
+        fn respond(&self, command: &str) {
+            if command.starts_with("/execute store result storage mctest:runner gametime") {
+                let gametime = {
+                    let mut gametime = self.gametime.borrow_mut();
+                    *gametime += 1;
+                    *gametime
+                };
+                if let Some(syncs) = self.reloading.borrow_mut().as_mut() {
+                    *syncs = syncs.saturating_sub(1);
+                }
+                let due: Vec<_> = self.scheduled.borrow_mut().extract_if(.., |(at, _)| *at <= gametime).collect();
+                due.iter().for_each(|(_, line)| self.tellraw(line));
+            } else if let Some(sync) = command.strip_prefix(r#"/tellraw @s [""#).filter(|c| c.starts_with(SYNC)) {
+                let (text, _) = sync.split_once(r#"","#).unwrap();
+                let gametime = *self.gametime.borrow();
+                self.tellraw(&format!("{text}{gametime}"));
+            } else if let Some(query) = command.strip_prefix("/tellraw @s ").filter(|c| c.contains(r#""insertion":"mctest:query""#)) {
+                let query: serde_json::Value = serde_json::from_str(query).unwrap();
+                let component = &query["extra"][0];
+                let value = if *component == serde_json::json!({ "nbt": "reloaded", "storage": "mctest:runner" }) {
+                    String::from(if *self.reloading.borrow() == Some(0) { "1b" } else { "" })
+                } else {
+                    self.world.get(&component.to_string()).cloned().unwrap_or_default()
+                };
+                self.tellraw(&serde_json::json!({ "text": "", "insertion": "mctest:query", "extra": [value] }).to_string());
+            } else {
+                self.commands.borrow_mut().push(command.to_owned());
+                if command == "/reload" {
+                    *self.reloading.borrow_mut() = Some(3);
+                }
+                if let Some(stalled) = self.stalled.take() {
+                    stalled.iter().for_each(|text| self.tellraw(text));
+                }
+                let run = self.runs.borrow_mut().get_mut(command).and_then(VecDeque::pop_front);
+                for line in run.as_ref().or(self.functions.get(command)).into_iter().flatten() {
+                    self.tellraw(line);
+                }
+                if let Some((ticks, lines)) = self.schedules.get(command) {
+                    let at = *self.gametime.borrow() + ticks;
+                    self.scheduled.borrow_mut().extend(lines.iter().map(|line| (at, line.clone())));
+                }
+                if self.stalls.iter().any(|stall| stall == command) {
+                    *self.stalled.borrow_mut() = Some(Vec::new());
+                }
+            }
+        }
+
+        fn tellraw(&self, text: &str) {
+            if let Some(stalled) = self.stalled.borrow_mut().as_mut() {
+                stalled.push(text.to_owned());
+                return;
+            }
+            // Lines that are components already are sent as is
+            let json = if text.starts_with('{') {
+                text.to_owned()
+            } else {
+                serde_json::json!({ "text": text }).to_string()
+            };
+            self.output.borrow_mut().extend(format!("{json}\n").bytes());
+        }
+    }
+
+    /// Records snapshots and restores of the world among the commands of the server.
+    impl Isolation<BufReader<FakeServer>, FakeServer> for FakeServer {
+        fn snapshot(&mut self) -> Result<()> {
+            self.commands.borrow_mut().push("snapshot".to_owned());
+            Ok(())
+        }
+
+        fn restore(&mut self) -> Result<(BufReader<FakeServer>, FakeServer)> {
+            self.commands.borrow_mut().push("restore".to_owned());
+            Ok((BufReader::new(self.clone()), self.clone()))
+        }
+    }
+
+    impl ReadTimeout for BufReader<FakeServer> {
+        fn set_read_timeout(&mut self, _timeout: Option<Duration>) {}
+    }
+
+    impl Write for FakeServer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let mut input = self.input.borrow_mut();
+            input.push_str(std::str::from_utf8(buf).unwrap());
+            while let Some(end) = input.find('\n') {
+                let command: String = input.drain(..=end).collect();
+                // Like the client, which can't send longer commands
+                if command.trim_end().trim_start_matches('/').len() >= MAX_COMMAND_LENGTH {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Command too long: {command}")));
+                }
+                self.respond(command.trim_end());
+            }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Read for FakeServer {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            // Nothing more is coming until another command is sent
+            if self.output.borrow().is_empty() {
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+            self.output.borrow_mut().read(buf)
+        }
+    }
+
+    #[test]
+    fn passing_test_has_no_diagnostics() -> Result<()> {
+        let server = FakeServer::new(&[("/function a", &["ok - a"])]);
+        let test_point = server.runner(&mut TapReporter::new(io::sink())).run_test(1, "/function a", 0)?.unwrap();
+
+        assert_eq!("ok 1 - a", test_point.to_string());
+        Ok(())
+    }
+
+    #[test]
+    fn failing_test_has_diagnostics() -> Result<()> {
+        let server = FakeServer::new(&[("/function b", &["not ok", "expected 1", "found 2"])]);
+        let test_point = server.runner(&mut TapReporter::new(io::sink())).run_test(2, "/function b", 0)?.unwrap();
+        let yaml = test_point.yaml.expect("Failing test should have diagnostics");
+
+        assert!(!test_point.ok);
+        assert_eq!(Some("/function b"), yaml["command"].as_str());
+        assert_eq!(Some(r#"{"text":"not ok"}"#), yaml["response"].as_str());
+        assert_eq!(Some(1), yaml["ticks"].as_i64());
+        assert_eq!(
+            vec!["expected 1", "found 2"],
+            yaml["extra"].as_sequence().unwrap().iter().map(|v| v.as_str().unwrap()).collect::<Vec<_>>()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_output_fails() -> Result<()> {
+        let server = FakeServer::new(&[("/function c", &["passed!"]), ("/function d", &["ok 7"])]);
+        let mut reporter = TapReporter::new(io::sink());
+        let mut runner = server.runner(&mut reporter);
+
+        let test_point = runner.run_test(1, "/function c", 0)?.unwrap();
+        assert_eq!("not ok 1 - /function c", test_point.to_string().lines().next().unwrap());
+        assert_eq!(Some("Invalid test output"), test_point.yaml.unwrap()["message"].as_str());
+
+        let test_point = runner.run_test(2, "/function d", 0)?.unwrap();
+        assert!(!test_point.ok);
+        assert_eq!(Some(2), test_point.number);
+        Ok(())
+    }
+
+    #[test]
+    fn subtest_summarises_children() -> Result<()> {
+        let server = FakeServer::new(&[
+            ("/function group", &["# Subtest: group", "1..2", "/function a", "/function b"]),
+            ("/function a", &["ok"]),
+            ("/function b", &["not ok # TODO"]),
+        ]);
+        let test_point = server.runner(&mut TapReporter::new(io::sink())).run_test(3, "/function group", 0)?.unwrap();
+
+        assert_eq!("ok 3 - group", test_point.to_string());
+        Ok(())
+    }
+
+    #[test]
+    fn suite_reports_tap() -> Result<()> {
+        let server = FakeServer::new(&[
+            ("/function mctest:plan", &["1..2"]),
+            ("/function mctest:list", &["/function a", "/function group"]),
+            ("/function a", &["ok - a"]),
+            ("/function group", &["# Subtest: group", "1..1", "/function b"]),
+            ("/function b", &["ok - b # SKIP not yet"]),
+        ]);
+
+        assert_eq!(
+            "TAP version 14\n1..2\nok 1 - a\n# Subtest: group\n    1..1\n    ok 1 - b # SKIP not yet\nok 2 - group\n\
+            # pass 1\n# fail 0\n# todo 0\n# skip 1\n",
+            server.tap()?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn timed_out_test_fails_and_run_continues() -> Result<()> {
+        let server = FakeServer::new(&[
+            ("/function mctest:plan", &["1..3"]),
+            ("/function mctest:list", &["/function a", "/function b", "/function c"]),
+            ("/function a", &["ok - a"]),
+            ("/function b", &["ok - b"]),
+            ("/function c", &["ok - c"]),
+        ])
+        .stalling(&["/function a"]);
+        let options = Options { timeout: Some(Duration::from_millis(10)), ..Options::default() };
+        let tap = server.tap_with(options)?;
+        let lines: Vec<&str> = tap.lines().collect();
+
+        assert_eq!("not ok 1 - /function a", lines[2]);
+        assert_eq!("  message: Timed out after 10ms", lines[4]);
+        assert!(tap.contains("  ...\nok 2 - b\nok 3 - c\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn global_timeout_fails_remaining_tests() -> Result<()> {
+        let server = FakeServer::new(&[("/function a", &["ok - a"])]);
+        let mut reporter = TapReporter::new(io::sink());
+        let mut runner = server.runner(&mut reporter);
+        runner.deadline = Some(Instant::now());
+
+        let test_point = runner.run_test(1, "/function a", 0)?.unwrap();
+        assert!(!test_point.ok);
+        assert_eq!(Some("Timed out after 0ns"), test_point.yaml.unwrap()["message"].as_str());
+        Ok(())
+    }
+
+    #[test]
+    fn summary_counts_flaky_tests() {
+        let mut summary = Summary::default();
+        let mut yaml = Yaml::new();
+        yaml.insert("flaky".into(), true.into());
+        summary.record(&TestPoint { yaml: Some(yaml), ..TestPoint::new(true) });
+
+        assert_eq!(Summary { passed: 1, flaky: 1, ..Summary::default() }, summary);
+    }
+
+    #[test]
+    fn summary_counts_outcomes() -> Result<()> {
+        let server = FakeServer::new(&[
+            ("/function mctest:plan", &["1..4"]),
+            ("/function mctest:list", &["/function a", "/function b", "/function c", "/function d"]),
+            ("/function a", &["ok"]),
+            ("/function b", &["not ok"]),
+            ("/function c", &["not ok # TODO"]),
+            ("/function d", &["ok # SKIP"]),
+        ]);
+        let summary = server.summary()?;
+
+        assert_eq!(Summary { passed: 1, failed: 1, todo: 1, skipped: 1, plan_mismatches: 0, bailed_out: false, flaky: 0, hits: Vec::new() }, summary);
+        assert!(!summary.success());
+        Ok(())
+    }
+
+    #[test]
+    fn plan_mismatch_is_unsuccessful() -> Result<()> {
+        let fewer = FakeServer::new(&[
+            ("/function mctest:plan", &["1..2"]),
+            ("/function mctest:list", &["/function a"]),
+            ("/function a", &["ok"]),
+        ]);
+        let more = FakeServer::new(&[
+            ("/function mctest:plan", &["1..1"]),
+            ("/function mctest:list", &["/function a", "/function a"]),
+            ("/function a", &["ok"]),
+        ]);
+
+        for server in [fewer, more] {
+            let summary = server.summary()?;
+            assert_eq!(0, summary.failed);
+            assert_eq!(1, summary.plan_mismatches);
+            assert!(!summary.success());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn plan_mismatch_is_reported() -> Result<()> {
+        let server = FakeServer::new(&[
+            ("/function mctest:plan", &["1..2"]),
+            ("/function mctest:list", &["/function a"]),
+            ("/function a", &["ok"]),
+        ]);
+
+        assert!(server.tap()?.starts_with("TAP version 14\n1..2\n# Planned 2 tests but 1 were listed\nok 1 - /function a\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn bail_out_aborts_run() -> Result<()> {
+        let server = FakeServer::new(&[
+            ("/function mctest:plan", &["1..2"]),
+            ("/function mctest:list", &["/function group", "/function b"]),
+            ("/function group", &["1..2", "/function a", "/function bail"]),
+            ("/function a", &["ok"]),
+            ("/function bail", &["Bail out! Missing dependency"]),
+            ("/function b", &["ok"]),
+        ]);
+
+        assert_eq!(
+            "TAP version 14\n1..2\n# Subtest: /function group\n    1..2\n    ok 1 - /function a\nBail out! Missing dependency\n\
+            # pass 1\n# fail 0\n# todo 0\n# skip 0\n",
+            server.tap()?
+        );
+        let summary = server.summary()?;
+        assert!(summary.bailed_out);
+        assert!(!summary.success());
+        Ok(())
+    }
+
+    #[test]
+    fn discovered_tests_are_planned() -> Result<()> {
+        let server = FakeServer::new(&[
+            ("/function example:test/a", &["ok"]),
+            ("/function example:test/b", &["ok"]),
+        ]);
+        let tests = vec!["example:test/a".to_owned(), "example:test/b".to_owned()];
+        let options = Options { tests: Some(tests), ..Options::default() };
+
+        assert!(server
+            .tap_with(options)?
+            .starts_with("TAP version 14\n1..2\nok 1 - /function example:test/a\nok 2 - /function example:test/b\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn filtered_tests_are_renumbered() -> Result<()> {
+        let server = FakeServer::new(&[
+            ("/function mctest:plan", &["1..3"]),
+            ("/function mctest:list", &["/function a", "/function b", "/function c"]),
+            ("/function b", &["ok"]),
+            ("/function c", &["ok"]),
+        ]);
+        let filter = Filter::new(&[], &["a".to_owned()])?;
+        let options = Options { filter, ..Options::default() };
+
+        assert!(server
+            .tap_with(options)?
+            .starts_with("TAP version 14\n1..2\nok 1 - /function b\nok 2 - /function c\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn plan_mismatch_is_reported_before_filtering() -> Result<()> {
+        let server = FakeServer::new(&[
+            ("/function mctest:plan", &["1..3"]),
+            ("/function mctest:list", &["/function a", "/function b"]),
+            ("/function b", &["ok"]),
+        ]);
+        let filter = Filter::new(&[], &["a".to_owned()])?;
+        let options = Options { filter, ..Options::default() };
+
+        let mut reporter = TapReporter::new(Vec::new());
+        let summary = server.runner_with(&mut reporter, options).run_suite()?;
+        assert_eq!(1, summary.plan_mismatches);
+        assert!(!summary.success());
+        assert!(String::from_utf8(reporter.into_inner())?
+            .starts_with("TAP version 14\n# Planned 3 tests but 2 were listed\n1..1\nok 1 - /function b\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn assertions_decide_outcome() -> Result<()> {
+        let server = FakeServer::new(&[
+            ("/function a", &[r#"{"text":"pass","insertion":"mctest:assert","extra":[" score #x vars matches 1"]}"#]),
+            ("/function b", &[
+                r#"{"text":"pass","insertion":"mctest:assert","extra":[" score #x vars matches 1"]}"#,
+                r#"{"text":"fail","insertion":"mctest:assert","extra":[" expected block at 0 0 0 to be stone"]}"#,
+            ]),
+            ("/function c", &["ok - c", r#"{"text":"fail","insertion":"mctest:assert","extra":[" expected 1 entities matching @e but found 0"]}"#]),
+        ]);
+        let mut reporter = TapReporter::new(io::sink());
+        let mut runner = server.runner(&mut reporter);
+
+        assert_eq!("ok 1 - /function a", runner.run_test(1, "/function a", 0)?.unwrap().to_string());
+
+        let test_point = runner.run_test(2, "/function b", 0)?.unwrap();
+        let yaml = test_point.yaml.unwrap();
+        assert!(!test_point.ok);
+        assert_eq!(Some("Assertion failed"), yaml["message"].as_str());
+        assert_eq!(Some("expected block at 0 0 0 to be stone"), yaml["assertions"][0].as_str());
+
+        let test_point = runner.run_test(3, "/function c", 0)?.unwrap();
+        assert!(!test_point.ok);
+        assert_eq!(Some("c"), test_point.description.as_deref());
+        Ok(())
+    }
+
+    #[test]
+    fn structured_result_is_preferred() -> Result<()> {
+        let server = FakeServer::new(&[
+            ("/function a", &[
+                "<Steve> ok 1 - not a result",
+                r#"{"text":"ok","color":"green","insertion":"mctest:result","extra":[{"text":"walks","bold":true,"insertion":"description"}]}"#,
+            ]),
+            ("/function b", &[
+                r#"{"text":"not ok","insertion":"mctest:result","extra":[{"text":"","insertion":"todo","extra":[{"translate":"block.minecraft.stone"}]},{"text":"landed early","insertion":"diagnostic"}]}"#,
+            ]),
+        ]);
+        let mut reporter = TapReporter::new(io::sink());
+        let mut runner = server.runner(&mut reporter);
+
+        assert_eq!("ok 1 - walks", runner.run_test(1, "/function a", 0)?.unwrap().to_string());
+
+        let test_point = runner.run_test(2, "/function b", 0)?.unwrap();
+        let yaml = test_point.yaml.as_ref().unwrap();
+        assert!(test_point.is_todo());
+        assert_eq!(Some("block.minecraft.stone"), test_point.directive.unwrap().reason.as_deref());
+        assert_eq!(Some("landed early"), yaml["diagnostics"][0].as_str());
+        Ok(())
+    }
+
+    #[test]
+    fn queries_read_world_state() -> Result<()> {
+        let server = FakeServer::new(&[]).with_world(&[
+            (serde_json::json!({ "score": { "name": "#counter", "objective": "vars" } }), "3"),
+            (serde_json::json!({ "nbt": "players[0]", "storage": "example:data" }), r#"{name:"Steve"}"#),
+            (serde_json::json!({ "nbt": "Health", "entity": "@e[type=zombie,limit=1]" }), "20.0f"),
+        ]);
+        let mut reporter = TapReporter::new(io::sink());
+        let mut runner = server.runner(&mut reporter);
+
+        assert_eq!(Some(3), runner.score("#counter", "vars")?);
+        assert_eq!(None, runner.score("#missing", "vars")?);
+        assert_eq!(
+            Some(query::parse_snbt(r#"{name:"Steve"}"#)?),
+            runner.storage("example:data", "players[0]")?
+        );
+        assert_eq!(None, runner.storage("example:data", "players[1]")?);
+        assert_eq!(
+            Some(quartz_nbt::NbtTag::Float(20.0)),
+            runner.entity_data("@e[type=zombie,limit=1]", "Health")?
+        );
+        assert_eq!(None, runner.block_data("0 64 0", "Items")?);
+        Ok(())
+    }
+
+    #[test]
+    fn expectations_fail_tests_leaving_other_world_state() -> Result<()> {
+        let server = FakeServer::new(&[("/function a", &["ok"]), ("/function b", &["ok"])]).with_world(&[
+            (serde_json::json!({ "score": { "name": "#counter", "objective": "vars" } }), "3"),
+            (serde_json::json!({ "nbt": "Items", "block": "0 64 0" }), r#"[{id:"minecraft:stone"}]"#),
+        ]);
+        let expectations = serde_yaml::from_str(
+            "a: [{ score: '#counter vars', equals: 3 }]\n\
+             b: [{ block: 0 64 0, path: Items, equals: '[]' }, { entity: '@e[type=pig,limit=1]', equals: null }]\n",
+        )?;
+        let options = Options { tests: Some(vec!["a".to_owned(), "b".to_owned()]), expectations, ..Options::default() };
+        let mut reporter = TapReporter::new(io::sink());
+        let mut runner = server.runner_with(&mut reporter, options);
+
+        let passing = runner.run_test(1, "/function a", 0)?.unwrap();
+        assert!(passing.ok);
+        let failing = runner.run_test(2, "/function b", 0)?.unwrap();
+        assert!(!failing.ok);
+        let yaml = failing.yaml.unwrap();
+        assert_eq!(Some("World state does not match expectations"), yaml["message"].as_str());
+        assert_eq!(
+            Some(r#"expected block 0 64 0 Items to be [] but found [{id:"minecraft:stone"}]"#),
+            yaml["expectations"][0].as_str()
+        );
+        assert_eq!(Some(1), yaml["expectations"].as_sequence().map(Vec::len));
+        Ok(())
+    }
+
+    #[test]
+    fn hooks_run_around_suite_and_tests() -> Result<()> {
+        let server = FakeServer::new(&[("/function a", &["ok"]), ("/function b", &["ok"])]);
+        let hooks = Hooks::find(|id| id != "mctest:teardown");
+        let options = Options { tests: Some(vec!["a".to_owned(), "b".to_owned()]), hooks, ..Options::default() };
+        server.tap_with(options)?;
+
+        assert_eq!(
+            vec![
+                "/gamerule sendCommandFeedback false",
+                "/function mctest:setup",
+                "/function mctest:before_each",
+                "/function a",
+                "/function mctest:after_each",
+                "/function mctest:before_each",
+                "/function b",
+                "/function mctest:after_each",
+            ],
+            *server.commands.borrow()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn bail_out_in_setup_skips_tests() -> Result<()> {
+        let server = FakeServer::new(&[("/function mctest:setup", &["Bail out! No world"]), ("/function a", &["ok"])]);
+        let options = Options {
+            tests: Some(vec!["a".to_owned()]),
+            hooks: Hooks::find(|id| id == "mctest:setup"),
+            ..Options::default()
+        };
+        let tap = server.tap_with(options)?;
+
+        assert!(tap.contains("Bail out! No world"));
+        assert!(!server.commands.borrow().contains(&"/function a".to_owned()));
+        Ok(())
+    }
+
+    #[test]
+    fn isolation_restores_world_between_tests() -> Result<()> {
+        let server = FakeServer::new(&[("/function a", &["ok"]), ("/function b", &["ok"])]);
+        let options = Options {
+            tests: Some(vec!["a".to_owned(), "b".to_owned()]),
+            hooks: Hooks::find(|id| id == "mctest:setup"),
+            ..Options::default()
+        };
+        let mut reporter = TapReporter::new(io::sink());
+        let mut isolation = server.clone();
+        let mut runner = server.runner_with(&mut reporter, options);
+        runner.isolation = Some(&mut isolation);
+        let summary = runner.run_suite()?;
+
+        assert_eq!(2, summary.passed);
+        assert_eq!(
+            vec![
+                "/gamerule sendCommandFeedback false",
+                "/function mctest:setup",
+                "snapshot",
+                "/function a",
+                "restore",
+                "/gamerule sendCommandFeedback false",
+                "/function b",
+            ],
+            *server.commands.borrow()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn isolation_is_skipped_after_global_timeout() -> Result<()> {
+        let server = FakeServer::new(&[("/function a", &["ok"])]);
+        let mut reporter = TapReporter::new(io::sink());
+        let mut isolation = server.clone();
+        let mut runner = server.runner(&mut reporter);
+        runner.isolation = Some(&mut isolation);
+        runner.dirty = true;
+        runner.deadline = Some(Instant::now());
+
+        let test_point = runner.run_test(2, "/function a", 0)?.unwrap();
+        assert!(!test_point.ok);
+        assert!(server.commands.borrow().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn global_timeout_during_isolation_fails_the_test() -> Result<()> {
+        /// Takes longer to restore than the global timeout leaves.
+        struct SlowIsolation(FakeServer);
+
+        impl Isolation<BufReader<FakeServer>, FakeServer> for SlowIsolation {
+            fn snapshot(&mut self) -> Result<()> {
+                self.0.snapshot()
+            }
+
+            fn restore(&mut self) -> Result<(BufReader<FakeServer>, FakeServer)> {
+                thread::sleep(Duration::from_millis(50));
+                self.0.restore()
+            }
+        }
+
+        let server = FakeServer::new(&[("/function a", &["ok"])]);
+        let mut reporter = TapReporter::new(io::sink());
+        let mut isolation = SlowIsolation(server.clone());
+        let mut runner = server.runner(&mut reporter);
+        runner.isolation = Some(&mut isolation);
+        runner.dirty = true;
+        runner.deadline = Some(Instant::now() + Duration::from_millis(10));
+
+        let test_point = runner.run_test(2, "/function a", 0)?.unwrap();
+        assert!(!test_point.ok);
+        assert_eq!(vec!["restore", "/gamerule sendCommandFeedback false"], *server.commands.borrow());
+        Ok(())
+    }
+
+    #[test]
+    fn async_test_waits_for_completion() -> Result<()> {
+        let server = FakeServer::new(&[
+            ("/function a", &[r#"{"text":"","insertion":"mctest:async"}"#]),
+            ("/function b", &[r#"{"text":"2","insertion":"mctest:async"}"#]),
+            ("/function c", &["ok"]),
+        ])
+        .scheduling(&[
+            ("/function a", 3, &[r#"{"text":"ok","insertion":"mctest:result","extra":[{"text":"a","insertion":"test"}]}"#]),
+            ("/function b", 5, &[r#"{"text":"ok","insertion":"mctest:result","extra":[{"text":"b","insertion":"test"}]}"#]),
+        ]);
+        let mut reporter = TapReporter::new(io::sink());
+        let mut runner = server.runner(&mut reporter);
+
+        let test_point = runner.run_test(1, "/function a", 0)?.unwrap();
+        assert!(test_point.ok);
+
+        let test_point = runner.run_test(2, "/function b", 0)?.unwrap();
+        assert!(!test_point.ok);
+        assert_eq!(Some("Did not complete within 2 ticks"), test_point.yaml.unwrap()["message"].as_str());
+
+        // The late result of b isn't mistaken for that of c
+        runner.run_test(3, "/function c", 0)?;
+        let test_point = runner.run_test(4, "/function c", 0)?.unwrap();
+        assert!(test_point.ok);
+        Ok(())
+    }
+
+    #[test]
+    fn parallel_results_are_merged_in_order() -> Result<()> {
+        let tests: Vec<String> = (1..=5).map(|i| format!("t{i}")).collect();
+        let options = Options { tests: Some(tests), ..Options::default() };
+        let mut reporter = TapReporter::new(Vec::new());
+        let summary = run_parallel(2, &mut reporter, options, |options, reporter| {
+            let shard = options.shard.unwrap();
+            // The second shard starts later, to finish out of order
+            if shard.index == 1 {
+                thread::sleep(Duration::from_millis(50));
+            }
+            FakeServer::new(&[
+                ("/function t1", &["ok - t1"]),
+                ("/function t2", &["ok - t2"]),
+                ("/function t3", &["ok - t3"]),
+                ("/function t4", &["not ok - t4"]),
+                ("/function t5", &["ok - t5"]),
+            ])
+            .runner_with(reporter, options)
+            .run_suite()
+        })?;
+
+        assert_eq!(4, summary.passed);
+        assert_eq!(1, summary.failed);
+        let tap = String::from_utf8(reporter.into_inner())?;
+        let lines: Vec<&str> = tap.lines().filter(|line| !line.starts_with(' ')).collect();
+        assert_eq!(
+            vec!["TAP version 14", "1..5", "ok 1 - t1", "ok 2 - t2", "ok 3 - t3", "not ok 4 - t4", "ok 5 - t5", "# pass 4", "# fail 1", "# todo 0", "# skip 0"],
+            lines
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn parallel_runners_stop_once_one_bails_out() -> Result<()> {
+        let tests: Vec<String> = (1..=4).map(|i| format!("t{i}")).collect();
+        let options = Options { tests: Some(tests), ..Options::default() };
+        let started = std::sync::Mutex::new(Vec::new());
+        let summary = run_parallel(2, &mut TapReporter::new(io::sink()), options, |options, reporter| {
+            // The second shard starts once the first bailed out
+            if options.shard.unwrap().index == 1 {
+                thread::sleep(Duration::from_millis(50));
+            }
+            let server = FakeServer::new(&[
+                ("/function t1", &["Bail out! broken"]),
+                ("/function t2", &["ok"]),
+                ("/function t3", &["ok"]),
+                ("/function t4", &["ok"]),
+            ]);
+            let summary = server.runner_with(reporter, options).run_suite();
+            started.lock().unwrap().extend(server.commands.take().into_iter().filter(|command| command.starts_with("/function")));
+            summary
+        })?;
+
+        assert!(summary.bailed_out);
+        assert_eq!(vec!["/function t1"], started.into_inner().unwrap());
+        Ok(())
+    }
+
+    #[test]
+    fn retried_tests_are_marked_flaky() -> Result<()> {
+        let server = FakeServer::new(&[("/function a", &["ok"]), ("/function b", &["not ok"])])
+            .answering_runs(&[("/function a", &[&["not ok"], &["not ok"]])]);
+        let options = Options { tests: Some(vec!["a".to_owned(), "b".to_owned()]), retries: 2, ..Options::default() };
+        let tap = server.tap_with(options)?;
+
+        assert!(tap.contains("ok 1 - /function a\n  ---\n  attempts: 3\n  flaky: true\n  ...\n"));
+        assert!(tap.contains("not ok 2 - /function b\n"));
+        // Both tests are run three times
+        assert_eq!(6, server.commands.borrow().iter().filter(|command| command.starts_with("/function")).count());
+        Ok(())
+    }
+
+    #[test]
+    fn snapshots_fail_tests_whose_output_changed() -> Result<()> {
+        let server = FakeServer::new(&[])
+            .answering_runs(&[("/function a", &[&["ok", "Hello"], &["ok", "Hello"], &["ok", "Goodbye"], &["ok", "Goodbye"]])])
+            .with_world(&[(serde_json::json!({ "nbt": "{}", "storage": "example:data" }), "{greeted:1b}")]);
+        let dir = tempdir::TempDir::new("mctest")?;
+        let snapshots = Snapshots { dir: dir.path().to_owned(), storages: vec!["example:data".to_owned()], ..Snapshots::default() };
+        let options = Options { tests: Some(vec!["a".to_owned()]), snapshots: Some(snapshots.clone()), ..Options::default() };
+
+        assert!(server
+            .tap_with(options.clone())?
+            .contains("not ok 1 - /function a\n  ---\n  message: No snapshot to compare with, wrote one\n"));
+        assert_eq!(
+            "--- chat /function a\nok\nHello\n--- storage example:data\n{greeted:1b}\n",
+            std::fs::read_to_string(snapshots.path("/function a"))?
+        );
+        assert!(server.tap_with(options.clone())?.contains("ok 1 - /function a\n"));
+
+        let tap = server.tap_with(options.clone())?;
+        assert!(tap.contains("not ok 1 - /function a\n  ---\n  message: Snapshot does not match\n"));
+        assert!(tap.contains("-Hello\n    +Goodbye\n"));
+
+        let options = Options { snapshots: Some(Snapshots { update: true, ..snapshots.clone() }), ..options };
+        assert!(server.tap_with(options)?.contains("ok 1 - /function a\n"));
+        assert!(std::fs::read_to_string(snapshots.path("/function a"))?.contains("Goodbye"));
+        Ok(())
+    }
+
+    #[test]
+    fn snapshots_of_timed_out_tests_leave_the_world_alone() -> Result<()> {
+        let server = FakeServer::new(&[("/function a", &["ok - a"]), ("/function b", &["ok - b"])]).stalling(&["/function a"]);
+        let dir = tempdir::TempDir::new("mctest")?;
+        let snapshots = Snapshots { dir: dir.path().to_owned(), storages: vec!["example:data".to_owned()], ..Snapshots::default() };
+        let options = Options {
+            timeout: Some(Duration::from_millis(10)),
+            tests: Some(vec!["a".to_owned(), "b".to_owned()]),
+            snapshots: Some(snapshots.clone()),
+            ..Options::default()
+        };
+        let tap = server.tap_with(options.clone())?;
+        assert!(tap.contains("not ok 1 - /function a\n  ---\n  message: Timed out after 10ms\n"));
+        assert!(tap.contains(" 2 - b\n"));
+        assert!(!snapshots.path("/function a").exists());
+
+        let mut reporter = TapReporter::new(io::sink());
+        let mut runner = server.runner_with(&mut reporter, options);
+        runner.deadline = Some(Instant::now());
+        let test_point = runner.run_test(1, "/function b", 0)?.unwrap();
+        assert_eq!(Some("Timed out after 0ns"), test_point.yaml.unwrap()["message"].as_str());
+        Ok(())
+    }
+
+    #[test]
+    fn coverage_is_collected_after_the_run() -> Result<()> {
+        let hits = serde_json::json!({ "nbt": "hits", "storage": "mctest:coverage" });
+        let server = FakeServer::new(&[("/function a", &["ok"]), ("/function mctest:coverage/hits", &[])])
+            .with_world(&[(hits, "[3,0,1]")]);
+        let options = Options { tests: Some(vec!["a".to_owned()]), coverage: Some(3), ..Options::default() };
+        let summary = server.runner_with(&mut TapReporter::new(io::sink()), options).run_suite()?;
+
+        assert_eq!(vec![3, 0, 1], summary.hits);
+        assert_eq!(Some("/function mctest:coverage/hits"), server.commands.borrow().last().map(String::as_str));
+        Ok(())
+    }
+
+    #[test]
+    fn benchmarks_run_after_warming_up() -> Result<()> {
+        let server = FakeServer::new(&[("/function a", &[]), ("/function b", &[])]);
+        let mut reporter = TapReporter::new(io::sink());
+        let options = BenchOptions { warmup: 2, iterations: 3, timeout: None };
+        let results = server.runner(&mut reporter).bench(&["a".to_owned(), "b".to_owned()], &options)?;
+
+        assert_eq!(vec!["(round trip)", "a", "b"], results.iter().map(|(function, _)| function.as_str()).collect::<Vec<_>>());
+        assert_eq!(5, server.commands.borrow().iter().filter(|command| *command == "/function a").count());
+        assert_eq!(5, server.commands.borrow().iter().filter(|command| *command == "/function b").count());
+        Ok(())
+    }
+
+    #[test]
+    fn coverage_is_collected_in_a_single_query() -> Result<()> {
+        let hits = serde_json::json!({ "nbt": "hits", "storage": "mctest:coverage" });
+        let server = FakeServer::new(&[("/function mctest:coverage/hits", &[])]).with_world(&[(hits, &format!("[{}]", ["2"; 1000].join(",")))]);
+        let options = Options { coverage: Some(1000), ..Options::default() };
+        let mut reporter = TapReporter::new(io::sink());
+        let mut runner = server.runner_with(&mut reporter, options);
+        runner.collect_coverage()?;
+
+        assert_eq!(vec![2; 1000], runner.summary.hits);
+        assert_eq!(vec!["/function mctest:coverage/hits"], *server.commands.borrow());
+        Ok(())
+    }
+
+    #[test]
+    fn coverage_is_collected_after_global_timeout() -> Result<()> {
+        let hits = serde_json::json!({ "nbt": "hits", "storage": "mctest:coverage" });
+        let server = FakeServer::new(&[("/function mctest:coverage/hits", &[])]).with_world(&[(hits, "[2]")]);
+        let options = Options { coverage: Some(1), ..Options::default() };
+        let mut reporter = TapReporter::new(io::sink());
+        let mut runner = server.runner_with(&mut reporter, options);
+        let deadline = Instant::now();
+        runner.deadline = Some(deadline);
+        runner.collect_coverage()?;
+
+        assert_eq!(vec![2], runner.summary.hits);
+        assert_eq!(Some(deadline), runner.deadline);
+        Ok(())
+    }
+
+    #[test]
+    fn shuffled_order_is_reproducible() -> Result<()> {
+        let tests: Vec<String> = (1..=10).map(|i| format!("t{i}")).collect();
+        let functions: Vec<String> = tests.iter().map(|test| format!("/function {test}")).collect();
+        let functions: Vec<(&str, &[&str])> = functions.iter().map(|command| (command.as_str(), &["ok"][..])).collect();
+        let server = FakeServer::new(&functions);
+        let options = Options { tests: Some(tests), shuffle: Some(42), ..Options::default() };
+
+        let tap = server.tap_with(options.clone())?;
+        let order = server.commands.take();
+        assert!(tap.starts_with("TAP version 14\n# Shuffled with seed 42\n1..10\n"));
+        assert_ne!(functions.iter().map(|(command, _)| command.to_string()).collect::<Vec<_>>(), order[1..]);
+
+        assert_eq!(tap, server.tap_with(options)?);
+        assert_eq!(order, server.commands.take());
+        Ok(())
+    }
+
+    #[test]
+    fn reload_waits_until_done() -> Result<()> {
+        let server = FakeServer::new(&[("/function a", &["ok"])]);
+        let options = Options { tests: Some(vec!["a".to_owned()]), reload: true, ..Options::default() };
+        server.tap_with(options)?;
+
+        assert_eq!(Some(0), *server.reloading.borrow());
+        assert_eq!(
+            vec!["/gamerule sendCommandFeedback false", "/data remove storage mctest:runner reloaded", "/reload", "/function a"],
+            *server.commands.borrow()
+        );
+        Ok(())
+    }
+}
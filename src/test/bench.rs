@@ -0,0 +1,149 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{self, BufRead, Write};
+use std::time::Duration;
+
+use super::report::TapReporter;
+use super::{Options, ReadTimeout, Runner, Session};
+
+/// A command that does nothing, timed to tell how long the round trip of a command to the server takes.
+const IDLE: &str = "/gamerule sendCommandFeedback false";
+/// Name the times of [`IDLE`] are reported by, so their spread can be weighed against the differences between benchmarks.
+pub const ROUND_TRIP: &str = "(round trip)";
+
+/// How often to run each benchmark.
+#[derive(Debug, Clone)]
+pub struct BenchOptions {
+    /// Runs to warm up the server with before measuring.
+    pub warmup: usize,
+    /// Runs to measure.
+    pub iterations: usize,
+    /// How long to wait for a single run before failing.
+    pub timeout: Option<Duration>,
+}
+
+/// How long a function took to run, in milliseconds.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Statistics {
+    pub mean: f64,
+    pub median: f64,
+    pub stddev: f64,
+}
+
+/// Statistics of earlier benchmarks by function id, to compare with.
+pub type Baseline = BTreeMap<String, Statistics>;
+
+impl Statistics {
+    /// The statistics of a non-empty list of samples in milliseconds.
+    pub fn of(samples: &[f64]) -> Self {
+        let mut samples = samples.to_vec();
+        samples.sort_by(f64::total_cmp);
+        let count = samples.len() as f64;
+        let mean = samples.iter().sum::<f64>() / count;
+        let middle = samples.len() / 2;
+        let median = if samples.len().is_multiple_of(2) {
+            (samples[middle - 1] + samples[middle]) / 2.0
+        } else {
+            samples[middle]
+        };
+        let variance = samples.iter().map(|sample| (sample - mean).powi(2)).sum::<f64>() / (count - 1.0).max(1.0);
+        Statistics { mean, median, stddev: variance.sqrt() }
+    }
+}
+
+/// Measures how long each of the `functions` takes to run on the server of `session`.
+pub fn run_benchmarks(session: &mut Session, functions: &[String], options: &BenchOptions) -> Result<Vec<(String, Statistics)>> {
+    let (reader, writer) = session
+        .connection
+        .take()
+        .ok_or(anyhow!("Lost the connection to the server"))?;
+    let mut reporter = TapReporter::new(io::sink());
+    let mut runner = Runner::new(reader, writer, &mut reporter, Options { timeout: options.timeout, ..Options::default() });
+    let results = runner.bench(functions, options);
+    session.connection = Some((runner.reader, runner.writer));
+    results
+}
+
+impl<R: BufRead + ReadTimeout, W: Write> Runner<'_, R, W> {
+    /// Runs each function as often as the options say, timing the runs after warming up. Times are
+    /// measured by the runner, less the median time of a command that does nothing to account for the
+    /// round trip. Its times are the first result, runs quicker than its median come out negative.
+    pub(super) fn bench(&mut self, functions: &[String], options: &BenchOptions) -> Result<Vec<(String, Statistics)>> {
+        self.query(IDLE)?;
+        self.time(IDLE, options.warmup)?;
+        let round_trip = Statistics::of(&self.time(IDLE, options.iterations)?);
+
+        let mut results = vec![(ROUND_TRIP.to_owned(), round_trip)];
+        for function in functions {
+            let command = format!("/function {function}");
+            self.time(&command, options.warmup)?;
+            let samples: Vec<f64> = self
+                .time(&command, options.iterations)?
+                .into_iter()
+                .map(|sample| sample - round_trip.median)
+                .collect();
+            results.push((function.clone(), Statistics::of(&samples)));
+        }
+        Ok(results)
+    }
+
+    /// How long each of `runs` runs of `command` took, in milliseconds.
+    fn time(&mut self, command: &str, runs: usize) -> Result<Vec<f64>> {
+        let mut samples = Vec::with_capacity(runs);
+        for _ in 0..runs {
+            let execution = self.run(command)?;
+            if let Some(timeout) = execution.timed_out {
+                return Err(anyhow!("`{command}` did not complete within {} seconds", timeout.as_secs_f64()));
+            }
+            samples.push(execution.duration.as_secs_f64() * 1000.0);
+        }
+        Ok(samples)
+    }
+}
+
+/// Describes the results of benchmarks, along with how much their mean changed since the `baseline`.
+pub fn report_benchmarks(results: &[(String, Statistics)], baseline: Option<&Baseline>) -> String {
+    let width = results.iter().map(|(function, _)| function.len()).max().unwrap_or(0);
+    let mut report = String::new();
+    for (function, statistics) in results {
+        let Statistics { mean, median, stddev } = statistics;
+        write!(report, "{function:width$}  mean {mean:.3} ms  median {median:.3} ms  stddev {stddev:.3} ms").ok();
+        if let Some(before) = baseline.and_then(|baseline| baseline.get(function)) {
+            let change = (mean - before.mean) / before.mean * 100.0;
+            write!(report, "  {change:+.1}% (was {:.3} ms)", before.mean).ok();
+        }
+        report.push('\n');
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn statistics_of_samples() {
+        let statistics = Statistics::of(&[6.0, 2.0, 1.0, 3.0]);
+
+        assert_eq!(3.0, statistics.mean);
+        assert_eq!(2.5, statistics.median);
+        assert!((statistics.stddev - 2.160).abs() < 0.001);
+    }
+
+    #[test]
+    fn report_compares_with_baseline() {
+        let results = vec![
+            ("example:bench/sort".to_owned(), Statistics { mean: 1.5, median: 1.25, stddev: 0.5 }),
+            ("example:bench/new".to_owned(), Statistics { mean: 2.0, median: 2.0, stddev: 0.0 }),
+        ];
+        let baseline = Baseline::from([("example:bench/sort".to_owned(), Statistics { mean: 1.0, median: 1.0, stddev: 0.1 })]);
+
+        assert_eq!(
+            "example:bench/sort  mean 1.500 ms  median 1.250 ms  stddev 0.500 ms  +50.0% (was 1.000 ms)\n\
+             example:bench/new   mean 2.000 ms  median 2.000 ms  stddev 0.000 ms\n",
+            report_benchmarks(&results, Some(&baseline))
+        );
+    }
+}
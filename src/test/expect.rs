@@ -0,0 +1,179 @@
+use anyhow::{anyhow, Result};
+use quartz_nbt::NbtTag;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{BufRead, Write};
+use std::path::Path;
+
+use super::query::parse_snbt;
+use super::{function_id, Execution, ReadTimeout, Runner};
+
+/// What the world should hold after tests, by the id of their function.
+pub type Expectations = BTreeMap<String, Vec<Expectation>>;
+
+/// A value the world should hold after a test, eg. `{ score: "#jumps vars", equals: 1 }` or
+/// `{ entity: "@e[type=pig,limit=1]", path: Health, equals: 10.0f }`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(try_from = "RawExpectation")]
+pub struct Expectation {
+    pub subject: Subject,
+    /// NBT path of the value within a storage, entity or block entity, the whole of it by default.
+    pub path: Option<String>,
+    /// The value, or `None` if there should be none. Scores are ints.
+    pub equals: Option<NbtTag>,
+}
+
+/// What holds the value of an expectation.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Subject {
+    /// A score, as `<target> <objective>`.
+    Score(String),
+    /// The id of a storage.
+    Storage(String),
+    /// A selector of a single entity.
+    Entity(String),
+    /// The position of a block entity, eg. `0 64 0`.
+    Block(String),
+}
+
+/// An expectation as written, before its value is parsed.
+#[derive(Deserialize)]
+struct RawExpectation {
+    #[serde(flatten)]
+    subject: Subject,
+    #[serde(default)]
+    path: Option<String>,
+    /// The score or SNBT of the value, or null if there should be none.
+    equals: serde_yaml::Value,
+}
+
+impl TryFrom<RawExpectation> for Expectation {
+    type Error = anyhow::Error;
+
+    fn try_from(raw: RawExpectation) -> Result<Self> {
+        let equals = match raw.equals {
+            serde_yaml::Value::Null => None,
+            serde_yaml::Value::String(value) => Some(value),
+            serde_yaml::Value::Number(value) => Some(value.to_string()),
+            serde_yaml::Value::Bool(value) => Some(value.to_string()),
+            _ => return Err(anyhow!("`equals` should be a score or SNBT")),
+        };
+        let equals = match (&raw.subject, equals) {
+            (Subject::Score(_), _) if raw.path.is_some() => return Err(anyhow!("Scores have no `path`")),
+            (Subject::Score(_), Some(value)) => {
+                let score = value.parse().map_err(|e| anyhow!("`{value}` is not a score: {e}"))?;
+                Some(NbtTag::Int(score))
+            }
+            (_, Some(value)) => Some(parse_snbt(&value)?),
+            (_, None) => None,
+        };
+        Ok(Expectation { subject: raw.subject, path: raw.path, equals })
+    }
+}
+
+/// Reads the expectations in a YAML file, failing on any that aren't valid or are for a test that doesn't exist.
+pub fn read_expectations(path: &Path, is_test: impl Fn(&str) -> bool) -> Result<Expectations> {
+    let yaml = fs::read(path).map_err(|e| anyhow!("Failed to read expectations {}: {e}", path.display()))?;
+    let expectations: Expectations =
+        serde_yaml::from_slice(&yaml).map_err(|e| anyhow!("Invalid expectations in {}: {e}", path.display()))?;
+    let unknown: Vec<&str> = expectations.keys().map(String::as_str).filter(|id| !is_test(id)).collect();
+    if !unknown.is_empty() {
+        return Err(anyhow!("Expectations in {} for tests that don't exist: {}", path.display(), unknown.join(", ")));
+    }
+    Ok(expectations)
+}
+
+impl std::fmt::Display for Expectation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.subject {
+            Subject::Score(score) => write!(f, "score {score}")?,
+            Subject::Storage(id) => write!(f, "storage {id}")?,
+            Subject::Entity(selector) => write!(f, "entity {selector}")?,
+            Subject::Block(pos) => write!(f, "block {pos}")?,
+        }
+        match &self.path {
+            Some(path) => write!(f, " {path}"),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<R: BufRead + ReadTimeout, W: Write> Runner<'_, R, W> {
+    /// Describes how the world differs from what's expected after the test of an execution.
+    pub(super) fn check_expectations(&mut self, execution: &Execution) -> Result<Vec<String>> {
+        let Some(expectations) = self.options.expectations.get(function_id(&execution.command)).cloned() else {
+            return Ok(Vec::new());
+        };
+
+        let mut unmet = Vec::new();
+        for expectation in &expectations {
+            let path = expectation.path.as_deref().unwrap_or("{}");
+            let actual = match &expectation.subject {
+                Subject::Score(score) => {
+                    let (target, objective) = score.split_once(' ').unwrap_or((score, ""));
+                    self.score(target, objective)?.map(NbtTag::Int)
+                }
+                Subject::Storage(id) => self.storage(id, path)?,
+                Subject::Entity(selector) => self.entity_data(selector, path)?,
+                Subject::Block(pos) => self.block_data(pos, path)?,
+            };
+            if actual != expectation.equals {
+                let expected = describe(expectation.equals.as_ref());
+                let actual = describe(actual.as_ref());
+                unmet.push(format!("expected {expectation} to be {expected} but found {actual}"));
+            }
+        }
+        Ok(unmet)
+    }
+}
+
+/// The SNBT of a value, or `nothing`.
+fn describe(value: Option<&NbtTag>) -> String {
+    value.map_or("nothing".to_owned(), NbtTag::to_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expectations_from_yaml() -> Result<()> {
+        let expectations: Expectations = serde_yaml::from_str(
+            "example:test/jump:\n\
+             - { score: '#jumps vars', equals: 1 }\n\
+             - { entity: '@e[type=pig,limit=1]', path: Health, equals: 10.0f }\n\
+             - { block: 0 64 0, equals: null }\n",
+        )?;
+
+        let expectations = &expectations["example:test/jump"];
+        assert_eq!(Subject::Score("#jumps vars".to_owned()), expectations[0].subject);
+        assert_eq!(Some(NbtTag::Int(1)), expectations[0].equals);
+        assert_eq!("entity @e[type=pig,limit=1] Health", expectations[1].to_string());
+        assert_eq!(Some(NbtTag::Float(10.0)), expectations[1].equals);
+        assert_eq!(None, expectations[2].equals);
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_expectations_are_rejected_when_read() -> Result<()> {
+        let dir = tempdir::TempDir::new("mctest")?;
+        let path = dir.path().join("expect.yaml");
+
+        fs::write(&path, "example:test/jump:\n- { score: '#jumps vars', equals: 'three' }\n")?;
+        let error = read_expectations(&path, |_| true).unwrap_err().to_string();
+        assert!(error.contains("expect.yaml"), "{error}");
+        assert!(error.contains("example:test/jump: `three` is not a score"), "{error}");
+        assert!(error.contains("line 2"), "{error}");
+
+        fs::write(&path, "example:test/jump:\n- { storage: 'example:state', equals: '{a:' }\n")?;
+        assert!(read_expectations(&path, |_| true).is_err());
+
+        fs::write(&path, "example:test/jump:\n- { score: '#jumps vars', equals: 1 }\n")?;
+        let error = read_expectations(&path, |id| id == "example:test/fall").unwrap_err().to_string();
+        assert!(error.contains("tests that don't exist: example:test/jump"), "{error}");
+        assert!(read_expectations(&path, |id| id == "example:test/jump").is_ok());
+        Ok(())
+    }
+}
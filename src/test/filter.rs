@@ -0,0 +1,90 @@
+use anyhow::Result;
+use glob::Pattern;
+
+use super::function_id;
+
+/// Selects tests by their function id, or their command if they aren't a function.
+/// Patterns containing glob syntax must match the whole id, others only need to be part of it.
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    include: Vec<Matcher>,
+    exclude: Vec<Matcher>,
+}
+
+#[derive(Debug, Clone)]
+enum Matcher {
+    Glob(Pattern),
+    Name(String),
+}
+
+impl Filter {
+    pub fn new(include: &[String], exclude: &[String]) -> Result<Self> {
+        Ok(Filter {
+            include: include.iter().map(|pattern| Matcher::new(pattern)).collect::<Result<_>>()?,
+            exclude: exclude.iter().map(|pattern| Matcher::new(pattern)).collect::<Result<_>>()?,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty()
+    }
+
+    /// Whether the test run by `command` is selected.
+    pub fn matches(&self, command: &str) -> bool {
+        let id = function_id(command);
+
+        (self.include.is_empty() || self.include.iter().any(|matcher| matcher.matches(id)))
+            && !self.exclude.iter().any(|matcher| matcher.matches(id))
+    }
+}
+
+impl Matcher {
+    fn new(pattern: &str) -> Result<Self> {
+        if pattern.contains(['*', '?', '[']) {
+            Ok(Matcher::Glob(Pattern::new(pattern)?))
+        } else {
+            Ok(Matcher::Name(pattern.to_owned()))
+        }
+    }
+
+    fn matches(&self, id: &str) -> bool {
+        match self {
+            Matcher::Glob(pattern) => pattern.matches(id),
+            Matcher::Name(name) => id.contains(name.as_str()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(include: &[&str], exclude: &[&str]) -> Filter {
+        let include: Vec<String> = include.iter().map(|s| s.to_string()).collect();
+        let exclude: Vec<String> = exclude.iter().map(|s| s.to_string()).collect();
+        Filter::new(&include, &exclude).unwrap()
+    }
+
+    #[test]
+    fn empty_filter_matches_everything() {
+        assert!(filter(&[], &[]).matches("/function example:test/jump"));
+        assert!(filter(&[], &[]).matches("/say hi"));
+    }
+
+    #[test]
+    fn match_by_name() {
+        let filter = filter(&["jump"], &[]);
+        assert!(filter.matches("/function example:test/jump"));
+        assert!(filter.matches("/function example:test/jump_high"));
+        assert!(!filter.matches("/function example:test/walk"));
+    }
+
+    #[test]
+    fn match_by_glob() {
+        let filter = filter(&["example:test/movement/*"], &["*/slow_*"]);
+        assert!(filter.matches("/function example:test/movement/jump"));
+        assert!(!filter.matches("/function example:test/movement/slow_walk"));
+        assert!(!filter.matches("/function other:test/movement/jump"));
+        assert!(!filter.matches("/function example:test/jump"));
+    }
+}
@@ -0,0 +1,147 @@
+use anyhow::Result;
+
+use super::report::Reporter;
+use super::tap::{BailOut, Plan, Subtest, TestPoint, Yaml};
+use super::{Execution, Message, Summary};
+
+/// Runs the suite on each of several `versions` with `run_version`, reporting the run on each
+/// version as a subtest. A version passes if its run succeeded.
+pub fn run_matrix<F>(versions: &[String], reporter: &mut dyn Reporter, mut run_version: F) -> Result<Summary>
+where
+    F: FnMut(&str, &mut dyn Reporter) -> Result<Summary>,
+{
+    reporter.start()?;
+    reporter.plan(&Plan { count: versions.len(), reason: None }, 0)?;
+
+    let mut summary = Summary::default();
+    for (index, version) in versions.iter().enumerate() {
+        let name = format!("Minecraft {version}");
+        reporter.subtest(&Subtest { name: Some(name.clone()) }, 0)?;
+        let mut test_point = TestPoint {
+            number: Some(index + 1),
+            description: Some(name),
+            ..TestPoint::new(false)
+        };
+        match run_version(version, &mut Nested(reporter)) {
+            Ok(run) => {
+                test_point.ok = run.success();
+                summary.add(&run);
+            }
+            // Other versions may still work
+            Err(e) => {
+                let mut yaml = Yaml::new();
+                yaml.insert("message".into(), format!("{e:#}").into());
+                test_point.yaml = Some(yaml);
+                summary.failed += 1;
+            }
+        }
+        reporter.test_point(&test_point, None, 0)?;
+        if summary.bailed_out {
+            break;
+        }
+    }
+
+    reporter.finish(&summary)?;
+    Ok(summary)
+}
+
+/// Reports a run one level deeper, as a subtest of a run started and finished by the wrapped reporter.
+struct Nested<'a>(&'a mut dyn Reporter);
+
+impl Reporter for Nested<'_> {
+    fn server_starting(&mut self, version: &str) -> Result<()> {
+        self.0.server_starting(version)
+    }
+
+    fn server_ready(&mut self) -> Result<()> {
+        self.0.server_ready()
+    }
+
+    fn start(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn shuffled(&mut self, seed: u64, depth: usize) -> Result<()> {
+        self.0.shuffled(seed, depth + 1)
+    }
+
+    fn plan(&mut self, plan: &Plan, depth: usize) -> Result<()> {
+        self.0.plan(plan, depth + 1)
+    }
+
+    fn plan_mismatch(&mut self, plan: &Plan, count: usize, depth: usize) -> Result<()> {
+        self.0.plan_mismatch(plan, count, depth + 1)
+    }
+
+    fn bail_out(&mut self, bail_out: &BailOut) -> Result<()> {
+        self.0.bail_out(bail_out)
+    }
+
+    fn subtest(&mut self, subtest: &Subtest, depth: usize) -> Result<()> {
+        self.0.subtest(subtest, depth + 1)
+    }
+
+    fn test_start(&mut self, number: usize, command: &str, depth: usize) -> Result<()> {
+        self.0.test_start(number, command, depth + 1)
+    }
+
+    fn chat_received(&mut self, message: &Message) -> Result<()> {
+        self.0.chat_received(message)
+    }
+
+    fn test_point(&mut self, test_point: &TestPoint, execution: Option<&Execution>, depth: usize) -> Result<()> {
+        self.0.test_point(test_point, execution, depth + 1)
+    }
+
+    fn finish(&mut self, _summary: &Summary) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::report::TapReporter;
+    use anyhow::anyhow;
+
+    #[test]
+    fn versions_are_reported_as_subtests() -> Result<()> {
+        let mut reporter = TapReporter::new(Vec::new());
+        let versions = vec!["1.20.1".to_owned(), "1.20.2".to_owned()];
+        let summary = run_matrix(&versions, &mut reporter, |version, reporter| {
+            if version == "1.20.1" {
+                return Err(anyhow!("Unsupported"));
+            }
+            reporter.start()?;
+            reporter.plan(&Plan { count: 1, reason: None }, 0)?;
+            let test_point = TestPoint { number: Some(1), ..TestPoint::new(true) };
+            reporter.test_point(&test_point, None, 0)?;
+            let summary = Summary { passed: 1, ..Summary::default() };
+            reporter.finish(&summary)?;
+            Ok(summary)
+        })?;
+
+        assert_eq!(
+            "TAP version 14\n1..2\n# Subtest: Minecraft 1.20.1\nnot ok 1 - Minecraft 1.20.1\n  ---\n  message: Unsupported\n  ...\n\
+             # Subtest: Minecraft 1.20.2\n    1..1\n    ok 1\nok 2 - Minecraft 1.20.2\n# pass 1\n# fail 1\n# todo 0\n# skip 0\n",
+            String::from_utf8(reporter.into_inner())?
+        );
+        assert_eq!(Summary { passed: 1, failed: 1, ..Summary::default() }, summary);
+        Ok(())
+    }
+
+    #[test]
+    fn shuffled_runs_are_reported_within_their_subtest() -> Result<()> {
+        let mut reporter = TapReporter::new(Vec::new());
+        run_matrix(&["1.20.2".to_owned()], &mut reporter, |_, reporter| {
+            reporter.start()?;
+            reporter.shuffled(42, 0)?;
+            reporter.plan(&Plan { count: 0, reason: None }, 0)?;
+            Ok(Summary::default())
+        })?;
+
+        assert!(String::from_utf8(reporter.into_inner())?
+            .starts_with("TAP version 14\n1..1\n# Subtest: Minecraft 1.20.2\n    # Shuffled with seed 42\n    1..0\n"));
+        Ok(())
+    }
+}
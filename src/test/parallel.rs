@@ -0,0 +1,238 @@
+use anyhow::{anyhow, Result};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::Arc;
+use std::thread;
+
+use super::report::Reporter;
+use super::tap::{BailOut, Plan, Subtest, TestPoint};
+use super::{Execution, Message, Options, Summary};
+
+/// The part of a suite run by one of several runners, every `count`th top level test starting at `index`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Shard {
+    pub index: usize,
+    pub count: usize,
+}
+
+impl Shard {
+    /// Whether the top level test `number` is part of this shard.
+    pub fn includes(&self, number: usize) -> bool {
+        (number - 1) % self.count == self.index
+    }
+
+    /// Whether this shard reports what concerns the whole suite, like its plan.
+    pub fn is_first(&self) -> bool {
+        self.index == 0
+    }
+}
+
+/// Runs the suite in `jobs` shards at once, each run by `run_shard` with the options of its shard,
+/// and reports their results to `reporter` merged in order. Once a shard bails out or fails, the
+/// others stop before their next test.
+pub fn run_parallel<F>(jobs: usize, reporter: &mut dyn Reporter, options: Options, run_shard: F) -> Result<Summary>
+where
+    F: Fn(Options, &mut dyn Reporter) -> Result<Summary> + Sync,
+{
+    reporter.start()?;
+    let (sender, receiver) = channel();
+    let mut merger = Merger::new(reporter);
+    let cancelled = Arc::new(AtomicBool::new(false));
+
+    thread::scope(|scope| {
+        let handles: Vec<_> = (0..jobs)
+            .map(|index| {
+                let shard = Some(Shard { index, count: jobs });
+                let options = Options { shard, cancelled: cancelled.clone(), ..options.clone() };
+                let cancelled = cancelled.clone();
+                let mut recorder = Recorder::new(sender.clone(), cancelled.clone());
+                let run_shard = &run_shard;
+                scope.spawn(move || {
+                    let summary = run_shard(options, &mut recorder);
+                    if summary.is_err() {
+                        cancelled.store(true, Ordering::Relaxed);
+                    }
+                    summary
+                })
+            })
+            .collect();
+        drop(sender);
+
+        for (number, events) in receiver {
+            merger.receive(number, events)?;
+        }
+        for handle in handles {
+            let summary = handle.join().map_err(|_| anyhow!("A test runner panicked"))??;
+            merger.summary.add_hits(&summary.hits);
+        }
+        merger.flush()
+    })?;
+
+    let summary = merger.summary;
+    reporter.finish(&summary)?;
+    Ok(summary)
+}
+
+/// Something reported by a shard, to be reported again in order.
+#[derive(Debug, Clone)]
+#[allow(clippy::large_enum_variant)]
+enum Event {
+    ServerReady,
+    Shuffled(u64, usize),
+    Plan(Plan, usize),
+    PlanMismatch(Plan, usize, usize),
+    BailOut(BailOut),
+    Subtest(Subtest, usize),
+    TestStart(usize, String, usize),
+    ChatReceived(Message),
+    TestPoint(TestPoint, Option<Execution>, usize),
+}
+
+/// Records what a shard reports, sending it on grouped by top level test. Events before the
+/// first test are grouped as test 0.
+struct Recorder {
+    sender: Sender<(usize, Vec<Event>)>,
+    /// Shared with the other shards, set when this one bails out.
+    cancelled: Arc<AtomicBool>,
+    number: usize,
+    events: Vec<Event>,
+}
+
+impl Recorder {
+    fn new(sender: Sender<(usize, Vec<Event>)>, cancelled: Arc<AtomicBool>) -> Self {
+        Recorder { sender, cancelled, number: 0, events: Vec::new() }
+    }
+
+    fn record(&mut self, event: Event) -> Result<()> {
+        self.events.push(event);
+        Ok(())
+    }
+
+    fn send(&mut self) -> Result<()> {
+        let events = std::mem::take(&mut self.events);
+        self.sender
+            .send((self.number, events))
+            .map_err(|_| anyhow!("Failed to send test results"))
+    }
+}
+
+impl Reporter for Recorder {
+    fn server_ready(&mut self) -> Result<()> {
+        self.record(Event::ServerReady)
+    }
+
+    fn start(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn shuffled(&mut self, seed: u64, depth: usize) -> Result<()> {
+        self.record(Event::Shuffled(seed, depth))
+    }
+
+    fn plan(&mut self, plan: &Plan, depth: usize) -> Result<()> {
+        self.record(Event::Plan(plan.clone(), depth))
+    }
+
+    fn plan_mismatch(&mut self, plan: &Plan, count: usize, depth: usize) -> Result<()> {
+        self.record(Event::PlanMismatch(plan.clone(), count, depth))
+    }
+
+    fn bail_out(&mut self, bail_out: &BailOut) -> Result<()> {
+        self.cancelled.store(true, Ordering::Relaxed);
+        self.record(Event::BailOut(bail_out.clone()))
+    }
+
+    fn subtest(&mut self, subtest: &Subtest, depth: usize) -> Result<()> {
+        self.record(Event::Subtest(subtest.clone(), depth))
+    }
+
+    fn test_start(&mut self, number: usize, command: &str, depth: usize) -> Result<()> {
+        if depth == 0 {
+            self.send()?;
+            self.number = number;
+        }
+        self.record(Event::TestStart(number, command.to_owned(), depth))
+    }
+
+    fn chat_received(&mut self, message: &Message) -> Result<()> {
+        self.record(Event::ChatReceived(message.clone()))
+    }
+
+    fn test_point(&mut self, test_point: &TestPoint, execution: Option<&Execution>, depth: usize) -> Result<()> {
+        self.record(Event::TestPoint(test_point.clone(), execution.cloned(), depth))
+    }
+
+    fn finish(&mut self, _summary: &Summary) -> Result<()> {
+        self.send()
+    }
+}
+
+/// Reports the events of top level tests in order as they become available, keeping count of
+/// the outcomes it reports. Nothing is reported after a `Bail out!`.
+struct Merger<'a> {
+    reporter: &'a mut dyn Reporter,
+    /// The top level test to report next.
+    next: usize,
+    pending: BTreeMap<usize, Vec<Event>>,
+    summary: Summary,
+}
+
+impl<'a> Merger<'a> {
+    fn new(reporter: &'a mut dyn Reporter) -> Self {
+        Merger { reporter, next: 1, pending: BTreeMap::new(), summary: Summary::default() }
+    }
+
+    fn receive(&mut self, number: usize, events: Vec<Event>) -> Result<()> {
+        if number == 0 {
+            return self.report(events);
+        }
+
+        self.pending.insert(number, events);
+        while let Some(events) = self.pending.remove(&self.next) {
+            self.report(events)?;
+            self.next += 1;
+        }
+        Ok(())
+    }
+
+    /// Reports the tests still pending once every shard is done, in order.
+    fn flush(&mut self) -> Result<()> {
+        for events in std::mem::take(&mut self.pending).into_values() {
+            self.report(events)?;
+        }
+        Ok(())
+    }
+
+    fn report(&mut self, events: Vec<Event>) -> Result<()> {
+        for event in events {
+            if self.summary.bailed_out {
+                return Ok(());
+            }
+            match event {
+                Event::ServerReady => self.reporter.server_ready()?,
+                Event::Shuffled(seed, depth) => self.reporter.shuffled(seed, depth)?,
+                Event::Plan(plan, depth) => self.reporter.plan(&plan, depth)?,
+                Event::PlanMismatch(plan, count, depth) => {
+                    self.summary.plan_mismatches += 1;
+                    self.reporter.plan_mismatch(&plan, count, depth)?
+                }
+                Event::BailOut(bail_out) => {
+                    self.summary.bailed_out = true;
+                    self.reporter.bail_out(&bail_out)?
+                }
+                Event::Subtest(subtest, depth) => self.reporter.subtest(&subtest, depth)?,
+                Event::TestStart(number, command, depth) => self.reporter.test_start(number, &command, depth)?,
+                Event::ChatReceived(message) => self.reporter.chat_received(&message)?,
+                Event::TestPoint(test_point, execution, depth) => {
+                    // Summaries of subtests aren't counted, their tests are
+                    if execution.is_some() {
+                        self.summary.record(&test_point);
+                    }
+                    self.reporter.test_point(&test_point, execution.as_ref(), depth)?
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,84 @@
+use anyhow::{anyhow, Result};
+use quartz_nbt::{snbt, NbtTag};
+use serde_json::{json, Value};
+use std::io::{BufRead, Write};
+
+use super::{component_to_plaintext, ReadTimeout, Runner};
+
+/// Marks the messages the runner reads world state from.
+const QUERY: &str = "mctest:query";
+
+/// Reading world state back into the runner, so tests can check it without coding every check in mcfunction.
+/// Values are read by having the server resolve `score` and `nbt` components of a `tellraw` to the runner,
+/// which unlike the replies of `scoreboard players get` and `data get` are sent regardless of `sendCommandFeedback`.
+impl<R: BufRead + ReadTimeout, W: Write> Runner<'_, R, W> {
+    /// The score of `target` for `objective`, or `None` if it has none.
+    pub(super) fn score(&mut self, target: &str, objective: &str) -> Result<Option<i32>> {
+        let value = self.resolve(json!({ "score": { "name": target, "objective": objective } }))?;
+        Ok(value.map(|value| value.parse()).transpose()?)
+    }
+
+    /// The NBT at `path` of the storage `id`, or `None` if there is none.
+    pub(super) fn storage(&mut self, id: &str, path: &str) -> Result<Option<NbtTag>> {
+        self.nbt(json!({ "nbt": path, "storage": id }))
+    }
+
+    /// The NBT at `path` of the entity matching `selector`, or `None` if there is none.
+    pub(super) fn entity_data(&mut self, selector: &str, path: &str) -> Result<Option<NbtTag>> {
+        self.nbt(json!({ "nbt": path, "entity": selector }))
+    }
+
+    /// The NBT at `path` of the block entity at `pos`, eg. `0 64 0`, or `None` if there is none.
+    pub(super) fn block_data(&mut self, pos: &str, path: &str) -> Result<Option<NbtTag>> {
+        self.nbt(json!({ "nbt": path, "block": pos }))
+    }
+
+    fn nbt(&mut self, component: Value) -> Result<Option<NbtTag>> {
+        self.resolve(component)?.map(|value| parse_snbt(&value)).transpose()
+    }
+
+    /// Has the server resolve `component`, returning the resulting text unless it's empty.
+    pub(super) fn resolve(&mut self, component: Value) -> Result<Option<String>> {
+        let command = resolve_command(&component);
+        let messages = self.query(&command)?;
+        let resolved = messages
+            .iter()
+            .find_map(|message| message.structured(QUERY))
+            .ok_or(anyhow!("No reply to query `{command}`"))?;
+
+        Ok(Some(component_to_plaintext(resolved)).filter(|value| !value.is_empty()))
+    }
+}
+
+/// The command having the server resolve `component` for [`Runner::resolve`].
+fn resolve_command(component: &Value) -> String {
+    let message = json!({ "text": "", "insertion": QUERY, "extra": [component] });
+    format!("/tellraw @s {message}")
+}
+
+/// Parses SNBT of any tag, eg. `3b` or `{name:"Steve"}`.
+pub fn parse_snbt(value: &str) -> Result<NbtTag> {
+    // Only compounds can be parsed on their own
+    let mut compound = snbt::parse(&format!("{{value:{value}}}"))?;
+    compound
+        .inner_mut()
+        .remove("value")
+        .ok_or(anyhow!("Failed to parse `{value}` as SNBT"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_snbt_of_any_tag() -> Result<()> {
+        assert_eq!(NbtTag::Byte(3), parse_snbt("3b")?);
+        assert_eq!(NbtTag::String("Steve".to_owned()), parse_snbt(r#""Steve""#)?);
+        let NbtTag::Compound(compound) = parse_snbt(r#"{name:"Steve",scores:[1,2]}"#)? else {
+            panic!("Expected a compound");
+        };
+        assert_eq!("Steve", compound.get::<_, &str>("name")?);
+        assert!(parse_snbt("{name:").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,140 @@
+mod jsonl;
+mod junit;
+
+use anyhow::Result;
+use clap::ValueEnum;
+use std::io::{self, Write};
+
+use super::tap::{BailOut, Element, Plan, Subtest, TestPoint, Version};
+use super::{Execution, Message, Summary};
+use jsonl::JsonlReporter;
+use junit::JunitReporter;
+
+/// Receives the results of a test run as they happen.
+pub trait Reporter {
+    /// Called before the server for `version` is prepared and started.
+    fn server_starting(&mut self, _version: &str) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called once the server has finished starting.
+    fn server_ready(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called once before any tests are reported.
+    fn start(&mut self) -> Result<()>;
+
+    /// Called after `start` if the order of the tests was shuffled with `seed`.
+    fn shuffled(&mut self, _seed: u64, _depth: usize) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called before the tests of a plan are run, `depth` subtests deep.
+    fn plan(&mut self, plan: &Plan, depth: usize) -> Result<()>;
+
+    /// Called when a plan at `depth` lists a different number of tests than it planned.
+    fn plan_mismatch(&mut self, plan: &Plan, count: usize, depth: usize) -> Result<()>;
+
+    /// Called when a test aborts the run. No more tests are reported after it.
+    fn bail_out(&mut self, bail_out: &BailOut) -> Result<()>;
+
+    /// Called when a test starts a subtest. Its tests are reported one level deeper,
+    /// followed by its summary test point at `depth`.
+    fn subtest(&mut self, subtest: &Subtest, depth: usize) -> Result<()>;
+
+    /// Called before the command of test `number` is run.
+    fn test_start(&mut self, _number: usize, _command: &str, _depth: usize) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called for every chat message received from the server.
+    fn chat_received(&mut self, _message: &Message) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called when a test finishes. `execution` is `None` for the summary of a subtest.
+    fn test_point(&mut self, test_point: &TestPoint, execution: Option<&Execution>, depth: usize) -> Result<()>;
+
+    /// Called once after all tests have run.
+    fn finish(&mut self, summary: &Summary) -> Result<()>;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    /// Test Anything Protocol, version 14
+    Tap,
+    /// JUnit XML
+    Junit,
+    /// JSON Lines stream of events
+    Jsonl,
+}
+
+impl Format {
+    /// Creates a reporter writing to stdout. `name` is the name of the test suite being run.
+    pub fn reporter(self, name: &str) -> Box<dyn Reporter> {
+        match self {
+            Format::Tap => Box::new(TapReporter::new(io::stdout())),
+            Format::Junit => Box::new(JunitReporter::new(io::stdout(), name)),
+            Format::Jsonl => Box::new(JsonlReporter::new(io::stdout())),
+        }
+    }
+}
+
+pub struct TapReporter<W> {
+    out: W,
+}
+
+impl<W: Write> TapReporter<W> {
+    pub fn new(out: W) -> Self {
+        TapReporter { out }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+
+    fn emit(&mut self, element: Element, depth: usize) -> Result<()> {
+        writeln!(self.out, "{}", element.indented(depth))?;
+        Ok(())
+    }
+}
+
+impl<W: Write> Reporter for TapReporter<W> {
+    fn start(&mut self) -> Result<()> {
+        self.emit(Element::Version(Version), 0)
+    }
+
+    fn shuffled(&mut self, seed: u64, depth: usize) -> Result<()> {
+        self.emit(Element::Comment(format!("Shuffled with seed {seed}")), depth)
+    }
+
+    fn plan(&mut self, plan: &Plan, depth: usize) -> Result<()> {
+        self.emit(Element::Plan(plan.clone()), depth)
+    }
+
+    fn plan_mismatch(&mut self, plan: &Plan, count: usize, depth: usize) -> Result<()> {
+        let comment = format!("Planned {} tests but {count} were listed", plan.count);
+        self.emit(Element::Comment(comment), depth)
+    }
+
+    fn bail_out(&mut self, bail_out: &BailOut) -> Result<()> {
+        self.emit(Element::BailOut(bail_out.clone()), 0)
+    }
+
+    fn subtest(&mut self, subtest: &Subtest, depth: usize) -> Result<()> {
+        self.emit(Element::Subtest(subtest.clone()), depth)
+    }
+
+    fn test_point(&mut self, test_point: &TestPoint, _execution: Option<&Execution>, depth: usize) -> Result<()> {
+        self.emit(Element::TestPoint(test_point.clone()), depth)
+    }
+
+    fn finish(&mut self, summary: &Summary) -> Result<()> {
+        let Summary { passed, failed, todo, skipped, .. } = summary;
+        let comment = format!("pass {passed}\nfail {failed}\ntodo {todo}\nskip {skipped}");
+        self.emit(Element::Comment(comment), 0)?;
+        self.out.flush()?;
+        Ok(())
+    }
+}
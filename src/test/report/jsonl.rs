@@ -0,0 +1,151 @@
+use anyhow::Result;
+use chrono::{SecondsFormat, Utc};
+use serde_json::{json, Value};
+use std::io::Write;
+
+use super::Reporter;
+use crate::test::tap::{BailOut, DirectiveType, Plan, Subtest, TestPoint};
+use crate::test::{Execution, Message, Summary};
+
+/// Reports every event of a test run as it happens, one JSON object per line.
+pub struct JsonlReporter<W> {
+    out: W,
+}
+
+impl<W: Write> JsonlReporter<W> {
+    pub fn new(out: W) -> Self {
+        JsonlReporter { out }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+
+    fn emit(&mut self, event: &str, mut fields: Value) -> Result<()> {
+        fields["event"] = event.into();
+        fields["timestamp"] = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true).into();
+        writeln!(self.out, "{fields}")?;
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+impl<W: Write> Reporter for JsonlReporter<W> {
+    fn server_starting(&mut self, version: &str) -> Result<()> {
+        self.emit("server-starting", json!({ "version": version }))
+    }
+
+    fn server_ready(&mut self) -> Result<()> {
+        self.emit("server-ready", json!({}))
+    }
+
+    fn start(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn shuffled(&mut self, seed: u64, _depth: usize) -> Result<()> {
+        self.emit("shuffled", json!({ "seed": seed }))
+    }
+
+    fn plan(&mut self, plan: &Plan, depth: usize) -> Result<()> {
+        self.emit("plan", json!({ "count": plan.count, "reason": plan.reason, "depth": depth }))
+    }
+
+    fn plan_mismatch(&mut self, plan: &Plan, count: usize, depth: usize) -> Result<()> {
+        self.emit("plan-mismatch", json!({ "planned": plan.count, "listed": count, "depth": depth }))
+    }
+
+    fn bail_out(&mut self, bail_out: &BailOut) -> Result<()> {
+        self.emit("bail-out", json!({ "reason": bail_out.reason }))
+    }
+
+    fn subtest(&mut self, subtest: &Subtest, depth: usize) -> Result<()> {
+        self.emit("subtest", json!({ "name": subtest.name, "depth": depth }))
+    }
+
+    fn test_start(&mut self, number: usize, command: &str, depth: usize) -> Result<()> {
+        self.emit("test-start", json!({ "number": number, "command": command, "depth": depth }))
+    }
+
+    fn chat_received(&mut self, message: &Message) -> Result<()> {
+        self.emit("chat-received", json!({ "text": message.text, "json": message.json }))
+    }
+
+    fn test_point(&mut self, test_point: &TestPoint, execution: Option<&Execution>, depth: usize) -> Result<()> {
+        let directive = test_point.directive.as_ref().map(|directive| match &directive.directive_type {
+            DirectiveType::Todo => "todo".to_owned(),
+            DirectiveType::Skip => "skip".to_owned(),
+            DirectiveType::Unknown(name) => name.clone(),
+        });
+        let reason = test_point
+            .directive
+            .as_ref()
+            .and_then(|directive| directive.reason.clone());
+
+        let mut fields = json!({
+            "number": test_point.number,
+            "ok": test_point.ok,
+            "description": test_point.description,
+            "directive": directive,
+            "reason": reason,
+            "depth": depth,
+            "subtest": execution.is_none(),
+        });
+        if let Some(execution) = execution {
+            fields["command"] = execution.command.clone().into();
+            fields["duration_ms"] = (execution.duration.as_millis() as u64).into();
+            fields["ticks"] = execution.ticks.into();
+        }
+        if let Some(yaml) = &test_point.yaml {
+            fields["diagnostics"] = serde_json::to_value(yaml)?;
+        }
+
+        self.emit("test-result", fields)
+    }
+
+    fn finish(&mut self, summary: &Summary) -> Result<()> {
+        self.emit(
+            "finished",
+            json!({
+                "success": summary.success(),
+                "passed": summary.passed,
+                "failed": summary.failed,
+                "todo": summary.todo,
+                "skipped": summary.skipped,
+                "plan_mismatches": summary.plan_mismatches,
+                "bailed_out": summary.bailed_out,
+                "flaky": summary.flaky,
+            }),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn events_are_lines_of_json() -> Result<()> {
+        let mut reporter = JsonlReporter::new(Vec::new());
+        reporter.server_starting("1.20.2")?;
+        reporter.plan(&"1..1".parse()?, 0)?;
+        reporter.test_point(&"not ok 1 - a # TODO later".parse()?, None, 0)?;
+        reporter.finish(&Summary { todo: 1, ..Summary::default() })?;
+
+        let output = String::from_utf8(reporter.into_inner())?;
+        let events: Vec<Value> = output.lines().map(serde_json::from_str).collect::<Result<_, _>>()?;
+
+        assert_eq!(4, events.len());
+        assert_eq!(json!("server-starting"), events[0]["event"]);
+        assert_eq!(json!("1.20.2"), events[0]["version"]);
+        assert_eq!(json!(1), events[1]["count"]);
+        assert_eq!(json!("test-result"), events[2]["event"]);
+        assert_eq!(json!(false), events[2]["ok"]);
+        assert_eq!(json!("todo"), events[2]["directive"]);
+        assert_eq!(json!("later"), events[2]["reason"]);
+        assert_eq!(json!("finished"), events[3]["event"]);
+        assert_eq!(json!(true), events[3]["success"]);
+        assert!(events.iter().all(|event| event["timestamp"].is_string()));
+        Ok(())
+    }
+}
@@ -0,0 +1,287 @@
+use anyhow::Result;
+use std::io::Write;
+use std::time::Duration;
+
+use super::Reporter;
+use crate::test::tap::{BailOut, Plan, Subtest, TestPoint};
+use crate::test::{Execution, Summary};
+
+// Schema from https://github.com/testmoapp/junitxml
+
+/// Collects test results into a JUnit XML report, written once the run finishes.
+/// Tests within subtests are flattened into the suite with the subtest names as their class.
+pub struct JunitReporter<W> {
+    out: W,
+    name: String,
+    /// Names of the subtests currently being run, with the number of test cases recorded before each started.
+    subtests: Vec<(String, usize)>,
+    test_cases: Vec<TestCase>,
+}
+
+struct TestCase {
+    name: String,
+    class_name: String,
+    time: Duration,
+    outcome: Outcome,
+}
+
+enum Outcome {
+    Passed,
+    Failed { message: String, body: String },
+    Skipped { message: String },
+}
+
+impl<W: Write> JunitReporter<W> {
+    pub fn new(out: W, name: &str) -> Self {
+        JunitReporter {
+            out,
+            name: name.to_owned(),
+            subtests: Vec::new(),
+            test_cases: Vec::new(),
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+
+    fn class_name(&self) -> String {
+        std::iter::once(self.name.as_str())
+            .chain(self.subtests.iter().map(|(name, _)| name.as_str()))
+            .collect::<Vec<_>>()
+            .join(".")
+    }
+
+    /// Records a problem with the run itself as a failed test case, so it isn't missed.
+    fn fail(&mut self, name: &str, message: String) {
+        self.test_cases.push(TestCase {
+            name: name.to_owned(),
+            class_name: self.class_name(),
+            time: Duration::ZERO,
+            outcome: Outcome::Failed { message, body: String::new() },
+        });
+    }
+
+    fn count(&self, predicate: impl Fn(&Outcome) -> bool) -> usize {
+        self.test_cases.iter().filter(|test_case| predicate(&test_case.outcome)).count()
+    }
+}
+
+impl<W: Write> Reporter for JunitReporter<W> {
+    fn start(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn plan(&mut self, _plan: &Plan, _depth: usize) -> Result<()> {
+        Ok(())
+    }
+
+    fn plan_mismatch(&mut self, plan: &Plan, count: usize, _depth: usize) -> Result<()> {
+        self.fail("plan", format!("Planned {} tests but {count} were listed", plan.count));
+        Ok(())
+    }
+
+    fn bail_out(&mut self, bail_out: &BailOut) -> Result<()> {
+        self.fail("Bail out!", bail_out.reason.clone().unwrap_or_default());
+        Ok(())
+    }
+
+    fn subtest(&mut self, subtest: &Subtest, _depth: usize) -> Result<()> {
+        self.subtests.push((subtest.name.clone().unwrap_or_default(), self.test_cases.len()));
+        Ok(())
+    }
+
+    fn test_point(&mut self, test_point: &TestPoint, execution: Option<&Execution>, depth: usize) -> Result<()> {
+        // The summary of a subtest is made up of the test cases already reported, unless it failed
+        // without any of them failing, eg. on a version of Minecraft the server didn't start on
+        if self.subtests.len() > depth {
+            let (name, start) = self.subtests.pop().unwrap_or_default();
+            let failed_within = self.test_cases[start..]
+                .iter()
+                .any(|test_case| matches!(test_case.outcome, Outcome::Failed { .. }));
+            if !test_point.ok && !test_point.is_todo() && !failed_within {
+                let message = message(test_point);
+                self.fail(test_point.description.as_deref().unwrap_or(&name), message);
+            }
+            return Ok(());
+        }
+        let Some(execution) = execution else {
+            return Ok(());
+        };
+
+        let reason = test_point
+            .directive
+            .as_ref()
+            .and_then(|directive| directive.reason.clone());
+        let outcome = if test_point.is_skip() {
+            Outcome::Skipped { message: reason.unwrap_or_default() }
+        } else if test_point.is_todo() && !test_point.ok {
+            Outcome::Skipped { message: format!("TODO {}", reason.unwrap_or_default()).trim_end().to_owned() }
+        } else if !test_point.ok {
+            let message = message(test_point);
+            let body = execution
+                .messages
+                .iter()
+                .map(|message| message.text.as_str())
+                .collect::<Vec<_>>()
+                .join("\n");
+            Outcome::Failed { message, body }
+        } else {
+            Outcome::Passed
+        };
+
+        self.test_cases.push(TestCase {
+            name: test_point.description.clone().unwrap_or_else(|| execution.command.clone()),
+            class_name: self.class_name(),
+            time: execution.duration,
+            outcome,
+        });
+
+        Ok(())
+    }
+
+    fn finish(&mut self, _summary: &Summary) -> Result<()> {
+        let tests = self.test_cases.len();
+        let failures = self.count(|outcome| matches!(outcome, Outcome::Failed { .. }));
+        let skipped = self.count(|outcome| matches!(outcome, Outcome::Skipped { .. }));
+        let time: Duration = self.test_cases.iter().map(|test_case| test_case.time).sum();
+        let name = escape(&self.name);
+        let time = time.as_secs_f64();
+
+        writeln!(self.out, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+        writeln!(self.out, r#"<testsuites name="mctest" tests="{tests}" failures="{failures}" skipped="{skipped}" time="{time:.3}">"#)?;
+        writeln!(self.out, r#"  <testsuite name="{name}" tests="{tests}" failures="{failures}" skipped="{skipped}" time="{time:.3}">"#)?;
+        for test_case in &self.test_cases {
+            let name = escape(&test_case.name);
+            let class_name = escape(&test_case.class_name);
+            let time = test_case.time.as_secs_f64();
+            let attributes = format!(r#"name="{name}" classname="{class_name}" time="{time:.3}""#);
+            match &test_case.outcome {
+                Outcome::Passed => {
+                    writeln!(self.out, "    <testcase {attributes}/>")?;
+                }
+                Outcome::Failed { message, body } => {
+                    writeln!(self.out, "    <testcase {attributes}>")?;
+                    writeln!(self.out, r#"      <failure message="{}">{}</failure>"#, escape(message), escape(body))?;
+                    writeln!(self.out, "    </testcase>")?;
+                }
+                Outcome::Skipped { message } => {
+                    writeln!(self.out, "    <testcase {attributes}>")?;
+                    writeln!(self.out, r#"      <skipped message="{}"/>"#, escape(message))?;
+                    writeln!(self.out, "    </testcase>")?;
+                }
+            }
+        }
+        writeln!(self.out, "  </testsuite>")?;
+        writeln!(self.out, "</testsuites>")?;
+        self.out.flush()?;
+
+        Ok(())
+    }
+}
+
+/// The message describing why a test failed.
+fn message(test_point: &TestPoint) -> String {
+    test_point
+        .yaml
+        .as_ref()
+        .and_then(|yaml| yaml.get("message"))
+        .and_then(|message| message.as_str())
+        .unwrap_or("not ok")
+        .to_owned()
+}
+
+/// Escapes text for use in XML attributes and content, dropping characters XML can't represent.
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            '\n' | '\r' | '\t' => escaped.push(c),
+            c if c.is_control() => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test::Message;
+
+    fn execution(command: &str, response: &str) -> Execution {
+        Execution {
+            command: command.to_owned(),
+            messages: vec![Message { json: String::new(), text: response.to_owned() }],
+            duration: Duration::from_millis(1500),
+            ticks: 0,
+            timed_out: None,
+            ticks_exceeded: None,
+            snapshot: None,
+            unmet_expectations: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn report() -> Result<()> {
+        let mut reporter = JunitReporter::new(Vec::new(), "pack");
+        reporter.start()?;
+        reporter.plan(&"1..2".parse()?, 0)?;
+        reporter.test_point(&"ok 1 - a & b".parse()?, Some(&execution("/function a", "ok")), 0)?;
+        reporter.subtest(&"# Subtest: group".parse()?, 0)?;
+        reporter.plan(&"1..2".parse()?, 1)?;
+        reporter.test_point(&"not ok 1 - c".parse()?, Some(&execution("/function c", "<fail>")), 1)?;
+        reporter.test_point(&"ok 2 # SKIP".parse()?, Some(&execution("/function d", "ok")), 1)?;
+        reporter.test_point(&"not ok 2 - group".parse()?, None, 0)?;
+        reporter.finish(&Summary::default())?;
+
+        let xml = String::from_utf8(reporter.into_inner())?;
+        assert_eq!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<testsuites name="mctest" tests="3" failures="1" skipped="1" time="4.500">
+  <testsuite name="pack" tests="3" failures="1" skipped="1" time="4.500">
+    <testcase name="a &amp; b" classname="pack" time="1.500"/>
+    <testcase name="c" classname="pack.group" time="1.500">
+      <failure message="not ok">&lt;fail&gt;</failure>
+    </testcase>
+    <testcase name="/function d" classname="pack.group" time="1.500">
+      <skipped message=""/>
+    </testcase>
+  </testsuite>
+</testsuites>
+"#,
+            xml
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn subtests_failing_without_test_cases_are_reported() -> Result<()> {
+        let mut reporter = JunitReporter::new(Vec::new(), "pack");
+        reporter.start()?;
+        reporter.plan(&"1..2".parse()?, 0)?;
+        reporter.subtest(&"# Subtest: Minecraft 1.20.1".parse()?, 0)?;
+        let mut failed: TestPoint = "not ok 1 - Minecraft 1.20.1".parse()?;
+        let mut yaml = crate::test::tap::Yaml::new();
+        yaml.insert("message".into(), "Server did not start".into());
+        failed.yaml = Some(yaml);
+        reporter.test_point(&failed, None, 0)?;
+        reporter.subtest(&"# Subtest: Minecraft 1.20.2".parse()?, 0)?;
+        reporter.test_point(&"ok 1 - a".parse()?, Some(&execution("/function a", "ok")), 1)?;
+        reporter.test_point(&"ok 2 - Minecraft 1.20.2".parse()?, None, 0)?;
+        reporter.finish(&Summary::default())?;
+
+        let xml = String::from_utf8(reporter.into_inner())?;
+        assert!(xml.contains(
+            "<testcase name=\"Minecraft 1.20.1\" classname=\"pack\" time=\"0.000\">\n      \
+             <failure message=\"Server did not start\"></failure>"
+        ));
+        assert!(xml.contains(r#"<testsuite name="pack" tests="2" failures="1" skipped="0""#));
+        Ok(())
+    }
+}
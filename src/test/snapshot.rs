@@ -0,0 +1,137 @@
+use anyhow::{anyhow, Result};
+use serde_json::json;
+use similar::TextDiff;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::PathBuf;
+
+use super::{function_id, Execution, ReadTimeout, Runner};
+
+/// Where snapshots of the output of tests are kept, and which world state they include besides chat.
+#[derive(Debug, Clone, Default)]
+pub struct Snapshots {
+    pub dir: PathBuf,
+    /// Whether to replace snapshots that don't match instead of failing their tests.
+    pub update: bool,
+    /// Ids of the storages to include.
+    pub storages: Vec<String>,
+    /// Scores to include, as `<target> <objective>`.
+    pub scores: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Comparison {
+    Matched,
+    /// The snapshot was updated or written for the first time, as snapshots are being updated.
+    Written,
+    /// There was no snapshot yet, so it was written without anything to compare with.
+    Created,
+    /// The snapshot differs, as described by the unified diff.
+    Mismatched(String),
+}
+
+impl Snapshots {
+    /// Where the snapshot of the test run by `command` is kept, eg. `example/test/walk.snap` for `example:test/walk`.
+    pub fn path(&self, command: &str) -> PathBuf {
+        let id: String = function_id(command)
+            .replace(':', "/")
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() || "_-./".contains(c) { c } else { '_' })
+            .collect();
+        let mut path = self.dir.clone();
+        path.extend(id.split('/').filter(|part| !matches!(*part, "" | "." | "..")));
+        path.set_extension("snap");
+        path
+    }
+
+    /// Compares `snapshot` of the test run by `command` to the one kept, keeping it instead if there
+    /// is none yet or snapshots are being updated.
+    pub fn compare(&self, command: &str, snapshot: &str) -> Result<Comparison> {
+        let path = self.path(command);
+        let kept = match fs::read_to_string(&path) {
+            Ok(kept) => Some(kept),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(anyhow!("Failed to read snapshot {}: {e}", path.display())),
+        };
+        match kept {
+            Some(kept) if kept == snapshot => return Ok(Comparison::Matched),
+            Some(kept) if !self.update => {
+                let diff = TextDiff::from_lines(kept.as_str(), snapshot)
+                    .unified_diff()
+                    .header("snapshot", "actual")
+                    .to_string();
+                return Ok(Comparison::Mismatched(diff));
+            }
+            _ => {}
+        }
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&path, snapshot)?;
+        Ok(if self.update { Comparison::Written } else { Comparison::Created })
+    }
+}
+
+impl<R: BufRead + ReadTimeout, W: Write> Runner<'_, R, W> {
+    /// Describes the chat output of an execution along with the selected world state, if snapshots are taken.
+    pub(super) fn take_snapshot(&mut self, execution: &Execution) -> Result<Option<String>> {
+        let Some(snapshots) = self.options.snapshots.clone() else {
+            return Ok(None);
+        };
+
+        let mut snapshot = format!("--- chat {}\n", execution.command);
+        for message in &execution.messages {
+            snapshot.push_str(&message.text);
+            snapshot.push('\n');
+        }
+        for storage in &snapshots.storages {
+            let nbt = self.resolve(json!({ "nbt": "{}", "storage": storage }))?;
+            snapshot.push_str(&format!("--- storage {storage}\n{}\n", nbt.unwrap_or_default()));
+        }
+        for score in &snapshots.scores {
+            let (target, objective) = score.split_once(' ').unwrap_or((score, ""));
+            let value = self.score(target, objective)?;
+            snapshot.push_str(&format!("--- score {score}\n{}\n", value.map(|value| value.to_string()).unwrap_or_default()));
+        }
+        Ok(Some(snapshot))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn snapshots_are_kept_then_compared() -> Result<()> {
+        let dir = TempDir::new("mctest")?;
+        let mut snapshots = Snapshots { dir: dir.path().to_owned(), ..Snapshots::default() };
+        assert_eq!(dir.path().join("example/test/walk.snap"), snapshots.path("/function example:test/walk"));
+
+        assert_eq!(Comparison::Created, snapshots.compare("/function example:test/walk", "Hello\n")?);
+        assert_eq!(Comparison::Matched, snapshots.compare("/function example:test/walk", "Hello\n")?);
+        let Comparison::Mismatched(diff) = snapshots.compare("/function example:test/walk", "Goodbye\n")? else {
+            panic!("Expected a mismatch");
+        };
+        assert!(diff.contains("-Hello\n+Goodbye\n"));
+
+        snapshots.update = true;
+        assert_eq!(Comparison::Written, snapshots.compare("/function example:test/walk", "Goodbye\n")?);
+        assert_eq!("Goodbye\n", fs::read_to_string(snapshots.path("/function example:test/walk"))?);
+        Ok(())
+    }
+
+    #[test]
+    fn unreadable_snapshots_are_kept() -> Result<()> {
+        let dir = TempDir::new("mctest")?;
+        let snapshots = Snapshots { dir: dir.path().to_owned(), update: true, ..Snapshots::default() };
+        fs::create_dir_all(snapshots.path("/function example:test/walk"))?;
+        assert!(snapshots.compare("/function example:test/walk", "Hello\n").is_err());
+
+        fs::write(snapshots.path("/function example:test/run"), [0xff, 0xfe])?;
+        assert!(snapshots.compare("/function example:test/run", "Hello\n").is_err());
+        assert_eq!(vec![0xff, 0xfe], fs::read(snapshots.path("/function example:test/run"))?);
+        Ok(())
+    }
+}
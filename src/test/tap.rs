@@ -0,0 +1,480 @@
+use anyhow::{anyhow, Result};
+use once_cell::sync::Lazy;
+use regex::Regex;
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+
+pub type Yaml = serde_yaml::Mapping;
+
+// Grammar from https://testanything.org/tap-version-14-specification.html
+
+static PLAN: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"^1\.\.(?<count>\d+)(?:\s+#\s*(?<reason>.*))?$").expect("Malformed regex")
+});
+
+static TEST_POINT: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"^(?<not>not )?ok\b(?:\s+(?<number>\d+))?(?:\s+-)?\s*(?<rest>.*)$")
+        .expect("Malformed regex")
+});
+
+static SUBTEST: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"^#\s*Subtest(?::\s*(?<name>.*))?$").expect("Malformed regex")
+});
+
+static BAIL_OUT: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?i)^Bail out!\s*(?<reason>.*)$").expect("Malformed regex")
+});
+
+static DIRECTIVE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"^(?<name>\S+)(?:\s+(?<reason>.*))?$").expect("Malformed regex")
+});
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Version;
+
+impl Display for Version {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "TAP version 14")
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Plan {
+    pub count: usize,
+    pub reason: Option<String>,
+}
+
+impl FromStr for Plan {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let captures = PLAN
+            .captures(s.trim())
+            .ok_or(anyhow!("Failed to parse `{s}` as a Plan"))?;
+        let count = captures["count"].parse()?;
+        let reason = captures.name("reason").map(|m| m.as_str().to_owned());
+
+        Ok(Plan { count, reason })
+    }
+}
+
+impl Display for Plan {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "1..{}", self.count)?;
+        if let Some(reason) = &self.reason {
+            write!(f, " # {reason}")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DirectiveType {
+    Todo,
+    Skip,
+    Unknown(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Directive {
+    pub directive_type: DirectiveType,
+    pub reason: Option<String>,
+}
+
+impl FromStr for Directive {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let captures = DIRECTIVE
+            .captures(s.trim())
+            .ok_or(anyhow!("Failed to parse `{s}` as a Directive"))?;
+        let name = &captures["name"];
+        // Directives are case insensitive and only need to start with the keyword, eg. `# skipped`
+        let directive_type = match name.to_lowercase() {
+            n if n.starts_with("todo") => DirectiveType::Todo,
+            n if n.starts_with("skip") => DirectiveType::Skip,
+            _ => DirectiveType::Unknown(name.to_owned()),
+        };
+        let reason = captures.name("reason").map(|m| m.as_str().to_owned());
+
+        Ok(Directive {
+            directive_type,
+            reason,
+        })
+    }
+}
+
+impl Display for Directive {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match &self.directive_type {
+            DirectiveType::Todo => write!(f, "TODO")?,
+            DirectiveType::Skip => write!(f, "SKIP")?,
+            DirectiveType::Unknown(name) => write!(f, "{}", escape(name))?,
+        }
+        if let Some(reason) = &self.reason {
+            write!(f, " {}", escape(reason))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TestPoint {
+    pub ok: bool,
+    pub number: Option<usize>,
+    pub description: Option<String>,
+    pub directive: Option<Directive>,
+    pub yaml: Option<Yaml>,
+}
+
+impl TestPoint {
+    pub fn new(ok: bool) -> Self {
+        TestPoint {
+            ok,
+            number: None,
+            description: None,
+            directive: None,
+            yaml: None,
+        }
+    }
+
+    pub fn is_todo(&self) -> bool {
+        matches!(
+            self.directive,
+            Some(Directive { directive_type: DirectiveType::Todo, .. })
+        )
+    }
+
+    pub fn is_skip(&self) -> bool {
+        matches!(
+            self.directive,
+            Some(Directive { directive_type: DirectiveType::Skip, .. })
+        )
+    }
+}
+
+impl FromStr for TestPoint {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let captures = TEST_POINT
+            .captures(s.trim())
+            .ok_or(anyhow!("Failed to parse `{s}` as a TestPoint"))?;
+        let ok = captures.name("not").is_none();
+        let number = captures
+            .name("number")
+            .map(|m| m.as_str().parse())
+            .transpose()?;
+        let (description, directive) = split_directive(&captures["rest"]);
+        let description = Some(unescape(description.trim_end())).filter(|d| !d.is_empty());
+        let directive = directive.map(str::parse).transpose()?;
+
+        Ok(TestPoint {
+            ok,
+            number,
+            description,
+            directive,
+            yaml: None,
+        })
+    }
+}
+
+impl Display for TestPoint {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if !self.ok {
+            write!(f, "not ")?;
+        }
+        write!(f, "ok")?;
+        if let Some(number) = self.number {
+            write!(f, " {number}")?;
+        }
+        if let Some(description) = &self.description {
+            write!(f, " - {}", escape(description))?;
+        }
+        if let Some(directive) = &self.directive {
+            write!(f, " # {directive}")?;
+        }
+        if let Some(yaml) = &self.yaml {
+            let yaml = serde_yaml::to_string(yaml).map_err(|_| fmt::Error)?;
+            write!(f, "\n  ---")?;
+            for line in yaml.lines() {
+                write!(f, "\n  {line}")?;
+            }
+            write!(f, "\n  ...")?;
+        }
+        Ok(())
+    }
+}
+
+/// The `# Subtest: name` comment introducing an indented child document.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Subtest {
+    pub name: Option<String>,
+}
+
+impl FromStr for Subtest {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let captures = SUBTEST
+            .captures(s.trim())
+            .ok_or(anyhow!("Failed to parse `{s}` as a Subtest"))?;
+        let name = captures
+            .name("name")
+            .map(|m| m.as_str().trim().to_owned())
+            .filter(|name| !name.is_empty());
+
+        Ok(Subtest { name })
+    }
+}
+
+impl Display for Subtest {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "# Subtest: {name}"),
+            None => write!(f, "# Subtest"),
+        }
+    }
+}
+
+/// A `Bail out!` line, aborting the whole test run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BailOut {
+    pub reason: Option<String>,
+}
+
+impl FromStr for BailOut {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let captures = BAIL_OUT
+            .captures(s.trim())
+            .ok_or(anyhow!("Failed to parse `{s}` as a BailOut"))?;
+        let reason = Some(captures["reason"].to_owned()).filter(|reason| !reason.is_empty());
+
+        Ok(BailOut { reason })
+    }
+}
+
+impl Display for BailOut {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "Bail out!")?;
+        if let Some(reason) = &self.reason {
+            write!(f, " {reason}")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Element {
+    Version(Version),
+    Plan(Plan),
+    TestPoint(TestPoint),
+    Subtest(Subtest),
+    BailOut(BailOut),
+    Comment(String),
+}
+
+impl Element {
+    /// Displays the element nested `depth` subtests deep.
+    pub fn indented(&self, depth: usize) -> String {
+        let indentation = "    ".repeat(depth);
+        self.to_string()
+            .lines()
+            .map(|line| format!("{indentation}{line}"))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+impl Display for Element {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Element::Version(version) => write!(f, "{version}"),
+            Element::Plan(plan) => write!(f, "{plan}"),
+            Element::TestPoint(test_point) => write!(f, "{test_point}"),
+            Element::Subtest(subtest) => write!(f, "{subtest}"),
+            Element::BailOut(bail_out) => write!(f, "{bail_out}"),
+            Element::Comment(comment) => {
+                let mut lines = comment.lines().peekable();
+                if lines.peek().is_none() {
+                    return write!(f, "#");
+                }
+                for (i, line) in lines.enumerate() {
+                    if i > 0 {
+                        writeln!(f)?;
+                    }
+                    write!(f, "# {line}")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Splits the text after a test point's number on the first unescaped `#`.
+fn split_directive(s: &str) -> (&str, Option<&str>) {
+    let mut escaped = false;
+    for (i, c) in s.char_indices() {
+        match c {
+            '\\' if !escaped => escaped = true,
+            '#' if !escaped => return (&s[..i], Some(&s[i + 1..])),
+            _ => escaped = false,
+        }
+    }
+    (s, None)
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', r"\\").replace('#', r"\#").replace('\n', " ")
+}
+
+fn unescape(s: &str) -> String {
+    let mut text = String::new();
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => text.push(chars.next().unwrap_or('\\')),
+            c => text.push(c),
+        }
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_plan() -> Result<()> {
+        assert_eq!(Plan { count: 2, reason: None }, "1..2".parse()?);
+        assert_eq!(
+            Plan { count: 0, reason: Some("no tests".to_owned()) },
+            "1..0 # no tests".parse()?
+        );
+        assert!("1.2".parse::<Plan>().is_err());
+        assert!("ok 1..2".parse::<Plan>().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn parse_test_point() -> Result<()> {
+        assert_eq!(TestPoint::new(true), "ok".parse()?);
+        assert_eq!(TestPoint::new(false), "not ok".parse()?);
+        assert_eq!(
+            TestPoint {
+                ok: true,
+                number: Some(3),
+                description: Some("fizz # buzz".to_owned()),
+                directive: None,
+                yaml: None,
+            },
+            r"ok 3 - fizz \# buzz".parse()?
+        );
+        assert_eq!(
+            TestPoint {
+                ok: false,
+                number: None,
+                description: Some("later".to_owned()),
+                directive: Some(Directive {
+                    directive_type: DirectiveType::Todo,
+                    reason: Some("not implemented".to_owned()),
+                }),
+                yaml: None,
+            },
+            "not ok later # todo not implemented".parse()?
+        );
+        assert!("okay".parse::<TestPoint>().is_err());
+        assert!("passed".parse::<TestPoint>().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn parse_directive() -> Result<()> {
+        assert_eq!(
+            Directive { directive_type: DirectiveType::Skip, reason: None },
+            "SKIPPED".parse()?
+        );
+        assert_eq!(
+            Directive {
+                directive_type: DirectiveType::Unknown("flaky".to_owned()),
+                reason: Some("sometimes".to_owned()),
+            },
+            "flaky sometimes".parse()?
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn display_round_trip() -> Result<()> {
+        for line in [
+            "ok",
+            "not ok 1",
+            "ok 2 - description",
+            r"ok 3 - escaped \# hash \\ slash",
+            "not ok 4 - broken # TODO fix later",
+            "ok 5 # SKIP",
+        ] {
+            assert_eq!(line, line.parse::<TestPoint>()?.to_string());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn parse_subtest() -> Result<()> {
+        assert_eq!(Subtest { name: Some("group".to_owned()) }, "# Subtest: group".parse()?);
+        assert_eq!(Subtest { name: None }, "# Subtest".parse()?);
+        assert!("# Comment".parse::<Subtest>().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn parse_bail_out() -> Result<()> {
+        assert_eq!(BailOut { reason: None }, "Bail out!".parse()?);
+        assert_eq!(BailOut { reason: Some("no server".to_owned()) }, "bail out! no server".parse()?);
+        assert!("Bail out".parse::<BailOut>().is_err());
+        assert_eq!("Bail out! no server", BailOut { reason: Some("no server".to_owned()) }.to_string());
+
+        Ok(())
+    }
+
+    #[test]
+    fn display_indented() {
+        let comment = Element::Comment("one\ntwo".to_owned());
+        assert_eq!("        # one\n        # two", comment.indented(2));
+        assert_eq!("ok 1", Element::TestPoint(TestPoint { number: Some(1), ..TestPoint::new(true) }).indented(0));
+    }
+
+    #[test]
+    fn display_yaml() {
+        let mut yaml = Yaml::new();
+        yaml.insert("message".into(), "failed".into());
+        yaml.insert("extra".into(), vec!["a", "b"].into());
+        let test_point = TestPoint {
+            number: Some(1),
+            yaml: Some(yaml),
+            ..TestPoint::new(false)
+        };
+
+        assert_eq!(
+            "not ok 1\n  ---\n  message: failed\n  extra:\n  - a\n  - b\n  ...",
+            test_point.to_string()
+        );
+        assert_eq!(
+            "    not ok 1\n      ---\n      message: failed",
+            Element::TestPoint(test_point).indented(1).lines().take(3).collect::<Vec<_>>().join("\n")
+        );
+    }
+
+    #[test]
+    fn display_comment() {
+        assert_eq!("# one\n# two", Element::Comment("one\ntwo".to_owned()).to_string());
+        assert_eq!("#", Element::Comment(String::new()).to_string());
+    }
+}
@@ -0,0 +1,259 @@
+use anyhow::{anyhow, Result};
+use serde_json::Value;
+
+use crate::datapack::Datapack;
+use crate::minecraft_client::{PROTOCOL_1_20_1, PROTOCOL_1_20_2};
+use crate::minecraft_server::retrieve_version_manifest;
+
+/// Separates the first and last version of a range, eg. `1.20.1..1.20.4`.
+const RANGE: &str = "..";
+/// Alias of the latest release.
+const LATEST: &str = "latest";
+/// Alias of the latest snapshot.
+const LATEST_SNAPSHOT: &str = "latest-snapshot";
+/// Alias of the newest release the client can connect to supporting the data pack format of the pack under test.
+const AUTO: &str = "auto";
+
+/// The releases the client can connect to, oldest first, with the version of the network protocol
+/// they speak and their data pack format.
+const SUPPORTED: &[(&str, i32, u32)] = &[
+    ("1.20", PROTOCOL_1_20_1, 15),
+    ("1.20.1", PROTOCOL_1_20_1, 15),
+    ("1.20.2", PROTOCOL_1_20_2, 18),
+];
+
+/// Resolves the versions given on the command line to version ids, in the order given without duplicates.
+/// Ranges include both ends and every version of the same type released in between, oldest first.
+/// Versions may also be given by the aliases `latest`, `latest-snapshot` and `auto`, which is the
+/// newest release the client can connect to supporting the data pack format of `datapack`.
+/// Fails if any of the versions is one the client can't connect to, before any server is set up.
+pub fn resolve(versions: &[String], datapack: &Datapack) -> Result<Vec<String>> {
+    let uses_manifest = versions
+        .iter()
+        .any(|version| version.contains(RANGE) || version == LATEST || version == LATEST_SNAPSHOT);
+    let manifest = if uses_manifest { retrieve_version_manifest()? } else { Value::Null };
+    let resolved = expand(versions, &manifest, datapack)?;
+    check_supported(&resolved)?;
+    Ok(resolved)
+}
+
+/// Fails with the versions among `versions` the client can't connect to, if any.
+fn check_supported(versions: &[String]) -> Result<()> {
+    let unsupported: Vec<&str> = versions
+        .iter()
+        .map(String::as_str)
+        .filter(|version| !is_supported(version))
+        .collect();
+    if unsupported.is_empty() {
+        return Ok(());
+    }
+    Err(anyhow!("mctest can only connect to Minecraft {}, not {}", supported(), unsupported.join(", ")))
+}
+
+/// The version of the network protocol `version` speaks, if the client can connect to it.
+pub fn protocol(version: &str) -> Option<i32> {
+    SUPPORTED
+        .iter()
+        .find(|(release, _, _)| *release == version)
+        .map(|(_, protocol, _)| *protocol)
+}
+
+fn is_supported(version: &str) -> bool {
+    protocol(version).is_some()
+}
+
+/// The releases the client can connect to, for error messages.
+fn supported() -> String {
+    SUPPORTED.iter().map(|(release, _, _)| *release).collect::<Vec<_>>().join(", ")
+}
+
+/// Resolves versions like [`resolve`], with the versions known from `manifest`.
+fn expand(versions: &[String], manifest: &Value, datapack: &Datapack) -> Result<Vec<String>> {
+    let alias = |version: &str| -> Result<String> {
+        let latest = match version {
+            LATEST => &manifest["latest"]["release"],
+            LATEST_SNAPSHOT => &manifest["latest"]["snapshot"],
+            AUTO => return newest_release_supporting(datapack),
+            _ => return Ok(version.to_owned()),
+        };
+        let latest = latest.as_str().ok_or(anyhow!("Unexpected version manifest format"))?;
+        // The latest versions soon outgrow the protocol of the client
+        if !is_supported(latest) {
+            return Err(anyhow!(
+                "`{version}` is Minecraft {latest}, but mctest can only connect to Minecraft {}",
+                supported()
+            ));
+        }
+        Ok(latest.to_owned())
+    };
+
+    let mut resolved = Vec::new();
+    for version in versions {
+        let Some((first, last)) = version.split_once(RANGE) else {
+            resolved.push(alias(version)?);
+            continue;
+        };
+        // The manifest lists the newest version first
+        let known = manifest["versions"]
+            .as_array()
+            .ok_or(anyhow!("Unexpected version manifest format"))?;
+        let index_of = |id: &str| {
+            known
+                .iter()
+                .position(|version| version["id"].as_str() == Some(id))
+                .ok_or(anyhow!("Could not identify version: {id}"))
+        };
+        let (first, last) = (index_of(&alias(first)?)?, index_of(&alias(last)?)?);
+        if first < last {
+            return Err(anyhow!("Version range {version} ends before it starts"));
+        }
+        let kind = &known[first]["type"];
+        let range = known[last..=first]
+            .iter()
+            .rev()
+            .filter(|known| known["type"] == *kind)
+            .filter_map(|known| known["id"].as_str());
+        resolved.extend(range.map(str::to_owned));
+    }
+    Ok(deduplicated(resolved))
+}
+
+/// The newest release the client can connect to supporting any of the data pack formats `datapack` supports.
+fn newest_release_supporting(datapack: &Datapack) -> Result<String> {
+    let formats = datapack.pack_formats()?;
+    SUPPORTED
+        .iter()
+        .rev()
+        .find(|(_, _, format)| formats.contains(format))
+        .map(|(release, _, _)| release.to_string())
+        .ok_or(anyhow!(
+            "`{AUTO}` found no version supporting data pack format {}: mctest can only connect to Minecraft {}",
+            formats.end(),
+            supported()
+        ))
+}
+
+fn deduplicated(versions: Vec<String>) -> Vec<String> {
+    let mut unique = Vec::new();
+    for version in versions {
+        if !unique.contains(&version) {
+            unique.push(version);
+        }
+    }
+    unique
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::fs;
+    use std::path::Path;
+    use tempdir::TempDir;
+
+    fn manifest() -> Value {
+        json!({
+            "latest": { "release": "1.20.4", "snapshot": "23w46a" },
+            "versions": [
+                { "id": "1.20.4", "type": "release" },
+                { "id": "1.20.3", "type": "release" },
+                { "id": "23w46a", "type": "snapshot" },
+                { "id": "1.20.2", "type": "release" },
+                { "id": "1.20.1", "type": "release" },
+                { "id": "1.20", "type": "release" },
+            ]
+        })
+    }
+
+    fn pack() -> Datapack {
+        Datapack::read(Path::new("packs/simple")).unwrap()
+    }
+
+    fn versions(versions: &[&str]) -> Vec<String> {
+        versions.iter().map(|version| version.to_string()).collect()
+    }
+
+    #[test]
+    fn ranges_include_releases_in_between() -> Result<()> {
+        assert_eq!(
+            versions(&["1.20.1", "1.20.2", "1.20.3", "1.20.4"]),
+            expand(&versions(&["1.20.1..1.20.4"]), &manifest(), &pack())?
+        );
+        assert_eq!(versions(&["23w46a"]), expand(&versions(&["23w46a..23w46a"]), &manifest(), &pack())?);
+
+        let supported = expand(&versions(&["1.20..1.20.2"]), &manifest(), &pack())?;
+        assert_eq!(versions(&["1.20", "1.20.1", "1.20.2"]), supported);
+        assert!(check_supported(&supported).is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn versions_are_deduplicated() -> Result<()> {
+        assert_eq!(
+            versions(&["1.20.4", "1.20.2", "1.20.3"]),
+            expand(&versions(&["1.20.4", "1.20.2..1.20.4"]), &manifest(), &pack())?
+        );
+        Ok(())
+    }
+
+    /// A pack supporting the data pack `formats`, in `dir`.
+    fn pack_supporting(dir: &TempDir, formats: &str) -> Result<Datapack> {
+        let pack_mcmeta = format!(r#"{{ "pack": {{ "pack_format": 18, "supported_formats": {formats} }} }}"#);
+        fs::write(dir.path().join("pack.mcmeta"), pack_mcmeta)?;
+        Datapack::read(dir.path())
+    }
+
+    #[test]
+    fn aliases_are_resolved() -> Result<()> {
+        let mut manifest = manifest();
+        manifest["latest"] = json!({ "release": "1.20.2", "snapshot": "1.20.2" });
+        assert_eq!(
+            versions(&["1.20.2"]),
+            expand(&versions(&["latest", "latest-snapshot", "auto"]), &manifest, &pack())?
+        );
+        assert_eq!(versions(&["1.20.1", "1.20.2"]), expand(&versions(&["1.20.1..latest"]), &manifest, &pack())?);
+        Ok(())
+    }
+
+    #[test]
+    fn aliases_of_unsupported_versions_fail() {
+        let error = expand(&versions(&["latest"]), &manifest(), &pack()).unwrap_err();
+        assert_eq!(
+            "`latest` is Minecraft 1.20.4, but mctest can only connect to Minecraft 1.20, 1.20.1, 1.20.2",
+            error.to_string()
+        );
+        assert!(expand(&versions(&["latest-snapshot"]), &manifest(), &pack()).is_err());
+    }
+
+    #[test]
+    fn auto_picks_a_supported_version() -> Result<()> {
+        let dir = TempDir::new("mctest")?;
+        let pack = pack_supporting(&dir, "[15, 26]")?;
+        assert_eq!(versions(&["1.20.2"]), expand(&versions(&["auto"]), &manifest(), &pack)?);
+        let pack = pack_supporting(&dir, "[10, 15]")?;
+        assert_eq!(versions(&["1.20.1"]), expand(&versions(&["auto"]), &manifest(), &pack)?);
+
+        let pack = pack_supporting(&dir, "[26, 41]")?;
+        let error = expand(&versions(&["auto"]), &manifest(), &pack).unwrap_err();
+        assert_eq!(
+            "`auto` found no version supporting data pack format 41: mctest can only connect to Minecraft 1.20, 1.20.1, 1.20.2",
+            error.to_string()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn unsupported_versions_fail_before_setting_up_a_server() {
+        assert!(resolve(&versions(&["1.20.2", "1.20.1"]), &pack()).is_ok());
+        let error = resolve(&versions(&["1.20.2", "1.20.4", "1.19.4"]), &pack()).unwrap_err();
+        assert_eq!("mctest can only connect to Minecraft 1.20, 1.20.1, 1.20.2, not 1.20.4, 1.19.4", error.to_string());
+        assert_eq!(Some(PROTOCOL_1_20_1), protocol("1.20"));
+        assert_eq!(None, protocol("1.20.4"));
+    }
+
+    #[test]
+    fn invalid_ranges_fail() {
+        assert!(expand(&versions(&["1.20.4..1.20.1"]), &manifest(), &pack()).is_err());
+        assert!(expand(&versions(&["1.20.1..1.21"]), &manifest(), &pack()).is_err());
+    }
+}
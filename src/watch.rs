@@ -0,0 +1,105 @@
+use anyhow::Result;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+/// How often to check for changes.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Modification times and sizes of the files of a datapack directory or archive, to tell when it changed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fingerprint(BTreeMap<PathBuf, (SystemTime, u64)>);
+
+impl Fingerprint {
+    pub fn of(path: &Path) -> Result<Self> {
+        let mut files = BTreeMap::new();
+        add_files(path, &mut files)?;
+        Ok(Fingerprint(files))
+    }
+}
+
+fn add_files(path: &Path, files: &mut BTreeMap<PathBuf, (SystemTime, u64)>) -> Result<()> {
+    let metadata = fs::metadata(path)?;
+    if metadata.is_dir() {
+        for entry in fs::read_dir(path)? {
+            add_files(&entry?.path(), files)?;
+        }
+    } else {
+        files.insert(path.to_owned(), (metadata.modified()?, metadata.len()));
+    }
+    Ok(())
+}
+
+/// Blocks until the datapack at `path` no longer matches `fingerprint`, returning its new fingerprint
+/// once it stopped changing, so that saving several files at once only counts as one change.
+pub fn wait_for_change(path: &Path, fingerprint: &Fingerprint) -> Result<Fingerprint> {
+    let mut current = fingerprint.clone();
+    loop {
+        thread::sleep(POLL_INTERVAL);
+        // Files may be missing in the middle of being saved
+        let Ok(next) = Fingerprint::of(path) else {
+            continue;
+        };
+        if next == current && next != *fingerprint {
+            return Ok(next);
+        }
+        current = next;
+    }
+}
+
+/// Runs `run` and then again each time `wait` returns, until waiting fails. Failed runs are reported and
+/// watching goes on, so that a broken datapack can be fixed without starting over.
+pub fn repeat(mut run: impl FnMut() -> Result<()>, mut wait: impl FnMut() -> Result<()>) -> Result<()> {
+    loop {
+        if let Err(e) = run() {
+            eprintln!("Error: {e:#}");
+        }
+        wait()?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+    use std::cell::Cell;
+    use tempdir::TempDir;
+
+    #[test]
+    fn fingerprint_changes_with_files() -> Result<()> {
+        let dir = TempDir::new("mctest")?;
+        fs::create_dir_all(dir.path().join("data/example"))?;
+        fs::write(dir.path().join("data/example/a.mcfunction"), "say a")?;
+        let fingerprint = Fingerprint::of(dir.path())?;
+        assert_eq!(fingerprint, Fingerprint::of(dir.path())?);
+
+        fs::write(dir.path().join("data/example/a.mcfunction"), "say changed")?;
+        assert_ne!(fingerprint, Fingerprint::of(dir.path())?);
+
+        fs::write(dir.path().join("data/example/b.mcfunction"), "say b")?;
+        let changed = wait_for_change(dir.path(), &fingerprint)?;
+        assert_ne!(fingerprint, changed);
+        assert_eq!(Fingerprint::of(dir.path())?, changed);
+        Ok(())
+    }
+
+    #[test]
+    fn repeat_goes_on_after_failed_runs() {
+        let runs = Cell::new(0);
+        let result = repeat(
+            || {
+                runs.set(runs.get() + 1);
+                if runs.get() == 1 {
+                    return Err(anyhow!("Failed to read pack.mcmeta"));
+                }
+                Ok(())
+            },
+            || if runs.get() < 3 { Ok(()) } else { Err(anyhow!("Stopped watching")) },
+        );
+
+        assert_eq!("Stopped watching", result.unwrap_err().to_string());
+        assert_eq!(3, runs.get());
+    }
+}